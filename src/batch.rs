@@ -0,0 +1,471 @@
+use std::{fmt::Write as _, fs, path::Path, time::Instant};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    device::parse_device,
+    disas::{memtoi, Address, Cpu, Ext, Register, Stat},
+    exec::step,
+    hook::Hook,
+    isa::Isa,
+    load::load_file,
+    memory::Memory,
+    parse_num,
+    sym::SymTab,
+    toml::{self, Table, Value},
+    MEMSIZE,
+};
+
+/// Instructions a case may execute when it sets no `limit`.
+const DEFAULT_LIMIT: u64 = 1_000_000;
+
+const CASE_KEYS: [&str; 10] = [
+    "name", "file", "ext", "device", "input", "regs", "mem", "pc", "limit", "expect",
+];
+const EXPECT_KEYS: [&str; 8] = [
+    "stat",
+    "regs",
+    "pc",
+    "mem",
+    "steps",
+    "min_steps",
+    "max_steps",
+    "stdout",
+];
+
+/// The result of one test case.
+pub struct Outcome {
+    pub name: String,
+    pub file: String,
+    /// Why the case could not be run, e.g. a file that does not load
+    pub error: Option<String>,
+    /// The expectations that did not hold
+    pub failures: Vec<String>,
+    pub count: u64,
+    pub seconds: f64,
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        self.error.is_none() && self.failures.is_empty()
+    }
+}
+
+/// Runs every `[[case]]` of a test spec. Files are found relative to the spec.
+pub fn run_spec(path: &str) -> Result<Vec<Outcome>> {
+    let text = fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+    let spec = toml::parse(&text).context(path.to_string())?;
+    let dir = Path::new(path).parent().unwrap_or(Path::new(""));
+
+    let cases = match spec.get("case") {
+        Some(Value::Array(v)) => v,
+        _ => bail!("{}: There are no [[case]] tables", path),
+    };
+    let mut outcomes = Vec::with_capacity(cases.len());
+    for (i, case) in cases.iter().enumerate() {
+        let case = match case {
+            Value::Table(t) => t,
+            _ => bail!("{}: case {} is not a table", path, i + 1),
+        };
+        let file = match case.get("file") {
+            Some(Value::Str(s)) => s.clone(),
+            _ => String::new(),
+        };
+        let name = match case.get("name") {
+            Some(Value::Str(s)) => s.clone(),
+            _ => format!("case {} ({})", i + 1, file),
+        };
+
+        let mut outcome = Outcome {
+            name,
+            file,
+            error: None,
+            failures: Vec::new(),
+            count: 0,
+            seconds: 0.0,
+        };
+        let start = Instant::now();
+        if let Err(e) = run_case(case, dir, &mut outcome) {
+            outcome.error = Some(format!("{:#}", e));
+        }
+        outcome.seconds = start.elapsed().as_secs_f64();
+        outcomes.push(outcome);
+    }
+
+    Ok(outcomes)
+}
+
+/// Loads and runs one case with its overrides, then checks its expectations.
+fn run_case(case: &Table, dir: &Path, outcome: &mut Outcome) -> Result<()> {
+    check_keys(case, &CASE_KEYS, "case")?;
+    let path = dir.join(&outcome.file);
+    let mut memory = Memory::new(MEMSIZE as usize);
+    let (hdr, _, symbols) = load_file(&path.to_string_lossy(), &mut memory.ram)?;
+
+    let mut ext = Vec::new();
+    for name in strings(case, "ext")? {
+        ext.push(Ext::from_str(&name, true).map_err(|e| anyhow!(e))?);
+    }
+    for spec in strings(case, "device")? {
+        let (addr, dev) = parse_device(&spec)?;
+        memory.attach(addr, dev)?;
+    }
+    let vectors = symbols.addr_of("vectors");
+
+    // never wait for the console
+    memory.journal.feed(match case.get("input") {
+        Some(Value::Str(s)) => s,
+        Some(_) => bail!("input must be a string"),
+        None => "",
+    });
+    memory.journal.capture();
+
+    let mut cpu = Cpu::new(hdr.entry as u64);
+    cpu.isa = Isa::for_program(&ext, vectors);
+    cpu.vectors = vectors;
+    for (name, val) in table(case, "regs")?.iter() {
+        cpu.reg[register(name)? as usize] = word(val, name, &symbols)?;
+    }
+    for (key, val) in table(case, "mem")?.iter() {
+        let mut addr = address(key, &symbols)?;
+        for quad in quads(val, key)? {
+            if !memory.store(addr, quad) {
+                bail!("Invalid address 0x{:x} in mem", addr);
+            }
+            addr = addr.wrapping_add(8);
+        }
+    }
+    if let Some(val) = case.get("pc") {
+        cpu.pc = word(val, "pc", &symbols)?;
+    }
+
+    let limit = match case.get("limit") {
+        Some(val) => int(val, "limit")?,
+        None => DEFAULT_LIMIT,
+    };
+    let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+    while cpu.stat == Stat::AOK && outcome.count < limit {
+        if step(&mut cpu, &mut memory, &mut hooks).is_none() {
+            break;
+        }
+        outcome.count += 1;
+    }
+    memory.halt()?;
+
+    check(case, &cpu, &memory, &symbols, limit, outcome)
+}
+
+/// Compares the final state with the `expect` table of a case. The status must be HLT
+/// unless another is expected.
+fn check(
+    case: &Table,
+    cpu: &Cpu,
+    memory: &Memory,
+    symbols: &SymTab,
+    limit: u64,
+    outcome: &mut Outcome,
+) -> Result<()> {
+    let expect = table(case, "expect")?;
+    check_keys(&expect, &EXPECT_KEYS, "expect")?;
+    let failures = &mut outcome.failures;
+
+    if cpu.stat == Stat::AOK {
+        failures.push(format!("Still running after {} instructions", limit));
+    } else {
+        let stat = match expect.get("stat") {
+            Some(Value::Str(s)) => s.to_uppercase(),
+            Some(_) => bail!("stat must be a string"),
+            None => String::from("HLT"),
+        };
+        if cpu.stat.to_string() != stat {
+            failures.push(format!("Status is {}, expected {}", cpu.stat, stat));
+        }
+    }
+
+    for (name, val) in table(&expect, "regs")?.iter() {
+        let reg = register(name)?;
+        let want = word(val, name, symbols)?;
+        if cpu.reg[reg as usize] != want {
+            failures.push(format!(
+                "%{} is 0x{:x}, expected 0x{:x}",
+                reg, cpu.reg[reg as usize], want
+            ));
+        }
+    }
+    if let Some(val) = expect.get("pc") {
+        let want = word(val, "pc", symbols)?;
+        if cpu.pc != want {
+            failures.push(format!("pc is 0x{:x}, expected 0x{:x}", cpu.pc, want));
+        }
+    }
+    for (key, val) in table(&expect, "mem")?.iter() {
+        let mut addr = address(key, symbols)?;
+        for want in quads(val, key)? {
+            match memtoi(&memory.ram, addr) {
+                Some(v) if v == want => (),
+                Some(v) => failures.push(format!(
+                    "M[0x{:x}] is 0x{:x}, expected 0x{:x}",
+                    addr, v, want
+                )),
+                None => bail!("Invalid address 0x{:x} in expect.mem", addr),
+            }
+            addr = addr.wrapping_add(8);
+        }
+    }
+
+    let count = outcome.count;
+    let bounds = [
+        ("steps", "exactly"),
+        ("min_steps", "at least"),
+        ("max_steps", "at most"),
+    ];
+    for (key, how) in bounds {
+        let want = match expect.get(key) {
+            Some(val) => int(val, key)?,
+            None => continue,
+        };
+        let ok = match key {
+            "steps" => count == want,
+            "min_steps" => count >= want,
+            _ => count <= want,
+        };
+        if !ok {
+            failures.push(format!(
+                "Executed {} instructions, expected {} {}",
+                count, how, want
+            ));
+        }
+    }
+
+    match expect.get("stdout") {
+        Some(Value::Str(want)) if memory.journal.captured() != want => failures.push(format!(
+            "Output is {:?}, expected {:?}",
+            memory.journal.captured(),
+            want
+        )),
+        Some(Value::Str(_)) | None => (),
+        Some(_) => bail!("stdout must be a string"),
+    }
+
+    Ok(())
+}
+
+/// Prints a line for every case, the reasons for each failure and a summary. Returns
+/// true if every case passed.
+pub fn report(outcomes: &[Outcome]) -> bool {
+    let (mut failed, mut errors) = (0, 0);
+    for o in outcomes.iter() {
+        if let Some(e) = &o.error {
+            errors += 1;
+            println!("ERROR {}: {}", o.name, e);
+        } else if !o.failures.is_empty() {
+            failed += 1;
+            println!("FAIL  {} ({} instructions)", o.name, o.count);
+            for f in o.failures.iter() {
+                println!("      {}", f);
+            }
+        } else {
+            println!("PASS  {} ({} instructions)", o.name, o.count);
+        }
+    }
+    println!(
+        "{} passed, {} failed, {} error(s)",
+        outcomes.len() - failed - errors,
+        failed,
+        errors
+    );
+
+    outcomes.iter().all(Outcome::passed)
+}
+
+/// Renders the outcomes as a JUnit XML test suite.
+pub fn junit(suite: &str, outcomes: &[Outcome]) -> String {
+    let failed = outcomes
+        .iter()
+        .filter(|o| o.error.is_none() && !o.failures.is_empty())
+        .count();
+    let errors = outcomes.iter().filter(|o| o.error.is_some()).count();
+    let time: f64 = outcomes.iter().map(|o| o.seconds).sum();
+
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    let _ = writeln!(
+        xml,
+        "<testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" time=\"{:.3}\">",
+        escape(suite),
+        outcomes.len(),
+        failed,
+        errors,
+        time
+    );
+    for o in outcomes.iter() {
+        let _ = write!(
+            xml,
+            "  <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+            escape(&o.name),
+            escape(&o.file),
+            o.seconds
+        );
+        if let Some(e) = &o.error {
+            let _ = writeln!(
+                xml,
+                ">\n    <error message=\"{}\"/>\n  </testcase>",
+                escape(e)
+            );
+        } else if let Some(first) = o.failures.first() {
+            let _ = writeln!(
+                xml,
+                ">\n    <failure message=\"{}\">{}</failure>\n  </testcase>",
+                escape(first),
+                escape(&o.failures.join("\n"))
+            );
+        } else {
+            xml += "/>\n";
+        }
+    }
+    xml += "</testsuite>\n";
+
+    xml
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn check_keys(table: &Table, keys: &[&str], what: &str) -> Result<()> {
+    match table.keys().find(|k| !keys.contains(&k.as_str())) {
+        Some(k) => bail!("Unknown key {} in {}", k, what),
+        None => Ok(()),
+    }
+}
+
+fn table(case: &Table, key: &str) -> Result<Table> {
+    match case.get(key) {
+        Some(Value::Table(t)) => Ok(t.clone()),
+        Some(_) => bail!("{} must be a table", key),
+        None => Ok(Table::new()),
+    }
+}
+
+fn strings(case: &Table, key: &str) -> Result<Vec<String>> {
+    match case.get(key) {
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| match v {
+                Value::Str(s) => Ok(s.clone()),
+                _ => bail!("{} must be an array of strings", key),
+            })
+            .collect(),
+        Some(_) => bail!("{} must be an array of strings", key),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn int(val: &Value, what: &str) -> Result<u64> {
+    match val {
+        Value::Int(n) => Ok(*n as u64),
+        _ => bail!("{} must be an integer", what),
+    }
+}
+
+/// An integer, or a string holding an address or symbol.
+fn word(val: &Value, what: &str, symbols: &SymTab) -> Result<u64> {
+    match val {
+        Value::Int(n) => Ok(*n as u64),
+        Value::Str(s) => address(s, symbols),
+        _ => bail!("{} must be an integer or an address", what),
+    }
+}
+
+/// A single quad or an array of consecutive quads.
+fn quads(val: &Value, what: &str) -> Result<Vec<u64>> {
+    match val {
+        Value::Array(items) => items.iter().map(|v| int(v, what)).collect(),
+        _ => Ok(vec![int(val, what)?]),
+    }
+}
+
+fn register(name: &str) -> Result<Register> {
+    Register::from_name(name).context(format!("Unknown register {}", name))
+}
+
+fn address(key: &str, symbols: &SymTab) -> Result<Address> {
+    match symbols.addr_of(key) {
+        Some(v) => Ok(v),
+        None => parse_num(key).context(format!("Unknown address {}", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "\
+0x000:                      | main:
+0x000: 30f00700000000000000 | \tirmovq $7,%rax
+0x00a: 00                   | \thalt
+0x00b:                      | double:
+0x00b: 6077                 | \taddq %rdi,%rdi
+0x00d: 00                   | \thalt
+0x010:                      | \t.align 8
+0x010:                      | value:
+0x010: 0300000000000000     | \t.quad 3
+";
+
+    const SPEC: &str = r#"
+[[case]]
+name = "entry"
+file = "prog.yo"
+[case.expect]
+regs = { rax = 7 }
+steps = 2
+
+[[case]]
+name = "symbols"
+file = "prog.yo"
+pc = "double"
+regs = { rdi = "value" }
+[case.expect]
+regs = { rdi = 0x20 }
+pc = "0xd"
+mem = { value = 3 }
+
+[[case]]
+name = "wrong"
+file = "prog.yo"
+[case.expect]
+stat = "ADR"
+regs = { rax = "value" }
+
+[[case]]
+name = "missing"
+file = "missing.yo"
+"#;
+
+    #[test]
+    fn run_spec_on_listing() {
+        let dir = std::env::temp_dir().join(format!("y86sim-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("prog.yo"), PROGRAM).unwrap();
+        let spec = dir.join("spec.toml");
+        fs::write(&spec, SPEC).unwrap();
+
+        let outcomes = run_spec(&spec.to_string_lossy()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(outcomes.len(), 4);
+        assert!(outcomes[0].passed(), "{:?}", outcomes[0].failures);
+        assert!(outcomes[1].passed(), "{:?}", outcomes[1].failures);
+        assert_eq!(outcomes[1].count, 2);
+
+        assert_eq!(
+            outcomes[2].failures,
+            ["Status is HLT, expected ADR", "%rax is 0x7, expected 0x10",]
+        );
+        assert!(outcomes[3].error.is_some());
+        assert!(!report(&outcomes));
+    }
+}
@@ -0,0 +1,65 @@
+use clap::Parser;
+use std::process::exit;
+use y86sim::link::{link, read_object};
+use y86sim::parse_num;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Link Mini-ELF objects into an executable", long_about = None)]
+struct Args {
+    /// Output file
+    #[arg(short = 'o', default_value = "a.o")]
+    output: String,
+
+    /// Start address of the code segment
+    #[arg(long = "code", default_value = "0x100", value_parser = parse_num)]
+    code: u64,
+
+    /// Size of the stack segment at the top of memory
+    #[arg(long = "stack", default_value = "0x100", value_parser = parse_num)]
+    stack: u64,
+
+    /// Mini-ELF object files
+    #[arg(required = true)]
+    objects: Vec<String>,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    let mut objects = Vec::with_capacity(args.objects.len());
+    for file in args.objects.iter() {
+        let object = std::fs::read(file)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| read_object(file, bytes));
+        match object {
+            Ok(v) => objects.push(v),
+            Err(e) => {
+                println!("Failed to read {}: {:#}", file, e);
+                exit(1);
+            }
+        }
+    }
+
+    let (code, stack) = match (u32::try_from(args.code), u32::try_from(args.stack)) {
+        (Ok(code), Ok(stack)) => (code, stack),
+        (Err(_), _) => {
+            println!("Code address 0x{:x} is out of range", args.code);
+            exit(1);
+        }
+        (_, Err(_)) => {
+            println!("Stack size 0x{:x} is out of range", args.stack);
+            exit(1);
+        }
+    };
+    let image = match link(&objects, code, stack) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{:#}", e);
+            exit(1);
+        }
+    };
+    if std::fs::write(&args.output, image).is_err() {
+        println!("Failed to write {}", args.output);
+        exit(1);
+    }
+}
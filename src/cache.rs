@@ -0,0 +1,268 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{disas::Address, hook::Hook, memory::Memory};
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Replace {
+    LRU,
+    FIFO,
+    RANDOM,
+}
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Write {
+    BACK,
+    THROUGH,
+}
+
+#[derive(Default, Clone, Copy)]
+struct Line {
+    valid: bool,
+    dirty: bool,
+    tag: Address,
+    stamp: u64, // time of last use (LRU) or of fill (FIFO)
+}
+
+#[derive(Default)]
+struct Stats {
+    reads: u64,
+    writes: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+/// Set-associative cache model attached to data memory accesses. Write-back caches
+/// allocate on a write miss; write-through caches do not. Device registers are not
+/// cached.
+pub struct Cache {
+    sets: usize,
+    ways: usize,
+    block: usize,
+    replace: Replace,
+    write: Write,
+    lines: Vec<Line>,
+    clock: u64,
+    rng: u64,
+    stats: BTreeMap<Address, Stats>,
+    writebacks: u64,
+    devices: Vec<(Address, Address)>,
+}
+
+impl Cache {
+    /// Parses a comma separated `--cache` spec such as
+    /// `sets=64,ways=2,block=16,replace=lru,write=back`. Omitted keys keep their defaults.
+    pub fn parse(spec: &str) -> Result<Cache> {
+        let (mut sets, mut ways, mut block): (usize, usize, usize) = (16, 2, 16);
+        let (mut replace, mut write) = (Replace::LRU, Write::BACK);
+
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let (key, val) = field.split_once('=').context("Expected key=value")?;
+            match key {
+                "sets" => sets = val.parse()?,
+                "ways" => ways = val.parse()?,
+                "block" => block = val.parse()?,
+                "replace" => {
+                    replace = match val {
+                        "lru" => Replace::LRU,
+                        "fifo" => Replace::FIFO,
+                        "random" => Replace::RANDOM,
+                        _ => bail!("Unknown replacement policy: {}", val),
+                    }
+                }
+                "write" => {
+                    write = match val {
+                        "back" => Write::BACK,
+                        "through" => Write::THROUGH,
+                        _ => bail!("Unknown write policy: {}", val),
+                    }
+                }
+                _ => bail!("Unknown cache option: {}", key),
+            }
+        }
+        ensure!(
+            sets > 0 && sets.is_power_of_two(),
+            "Sets must be a power of two"
+        );
+        ensure!(
+            block > 0 && block.is_power_of_two(),
+            "Block size must be a power of two"
+        );
+        ensure!(ways > 0, "Ways must be positive");
+
+        Ok(Cache {
+            sets,
+            ways,
+            block,
+            replace,
+            write,
+            lines: vec![Line::default(); sets * ways],
+            clock: 0,
+            rng: 0x2545f4914f6cdd1d,
+            stats: BTreeMap::new(),
+            writebacks: 0,
+            devices: Vec::new(),
+        })
+    }
+
+    /// Lets accesses to the devices mapped in `memory` bypass the cache.
+    pub fn bypass(&mut self, memory: &Memory) {
+        self.devices = memory
+            .regions()
+            .map(|(start, end, _)| (start, end))
+            .collect();
+    }
+
+    /// Simulates a quad access, touching every block it overlaps. The access hits only if
+    /// every block it touches is cached.
+    fn access(&mut self, pc: Address, addr: Address, write: bool) {
+        if self
+            .devices
+            .iter()
+            .any(|(start, end)| addr >= *start && addr < *end)
+        {
+            return;
+        }
+
+        let block = self.block as Address;
+        let first = addr / block;
+        let last = addr.saturating_add(7) / block;
+        let mut hit = true;
+        for blk in first..=last {
+            hit &= self.access_block(pc, blk, write);
+        }
+
+        let stats = self.stats.entry(pc).or_default();
+        if write {
+            stats.writes += 1;
+        } else {
+            stats.reads += 1;
+        }
+        if hit {
+            stats.hits += 1;
+        } else {
+            stats.misses += 1;
+        }
+    }
+
+    /// Looks up one block, filling it on a miss. Returns true on a hit.
+    fn access_block(&mut self, pc: Address, blk: Address, write: bool) -> bool {
+        self.clock += 1;
+        let set = (blk % self.sets as Address) as usize;
+        let tag = blk / self.sets as Address;
+        let ways = &mut self.lines[set * self.ways..(set + 1) * self.ways];
+        let stats = self.stats.entry(pc).or_default();
+
+        if let Some(line) = ways.iter_mut().find(|l| l.valid && l.tag == tag) {
+            if self.replace == Replace::LRU {
+                line.stamp = self.clock;
+            }
+            if write && self.write == Write::BACK {
+                line.dirty = true;
+            }
+            return true;
+        }
+
+        // write-through caches do not allocate on a write miss
+        if write && self.write == Write::THROUGH {
+            return false;
+        }
+
+        let victim = match ways.iter().position(|l| !l.valid) {
+            Some(i) => i,
+            None => {
+                stats.evictions += 1;
+                match self.replace {
+                    Replace::LRU | Replace::FIFO => {
+                        let mut i = 0;
+                        for (j, line) in ways.iter().enumerate() {
+                            if line.stamp < ways[i].stamp {
+                                i = j;
+                            }
+                        }
+                        i
+                    }
+                    Replace::RANDOM => {
+                        // xorshift keeps runs reproducible
+                        self.rng ^= self.rng << 13;
+                        self.rng ^= self.rng >> 7;
+                        self.rng ^= self.rng << 17;
+                        (self.rng % self.ways as u64) as usize
+                    }
+                }
+            }
+        };
+
+        if ways[victim].valid && ways[victim].dirty {
+            self.writebacks += 1;
+        }
+        ways[victim] = Line {
+            valid: true,
+            dirty: write,
+            tag,
+            stamp: self.clock,
+        };
+
+        false
+    }
+}
+
+impl Hook for Cache {
+    fn mem_read(&mut self, pc: Address, addr: Address) {
+        self.access(pc, addr, false);
+    }
+
+    fn mem_write(&mut self, pc: Address, addr: Address) {
+        self.access(pc, addr, true);
+    }
+
+    fn report(&self) {
+        println!(
+            "Cache: {} sets, {} ways, {}-byte blocks, {}, {}",
+            self.sets,
+            self.ways,
+            self.block,
+            match self.replace {
+                Replace::LRU => "LRU",
+                Replace::FIFO => "FIFO",
+                Replace::RANDOM => "random",
+            },
+            match self.write {
+                Write::BACK => "write-back",
+                Write::THROUGH => "write-through",
+            }
+        );
+        println!("  PC       Reads    Writes   Hits     Misses   Evictions");
+
+        let mut total = Stats::default();
+        for (pc, stats) in self.stats.iter() {
+            println!(
+                "  0x{:03x}    {:<8} {:<8} {:<8} {:<8} {}",
+                pc, stats.reads, stats.writes, stats.hits, stats.misses, stats.evictions
+            );
+            total.reads += stats.reads;
+            total.writes += stats.writes;
+            total.hits += stats.hits;
+            total.misses += stats.misses;
+            total.evictions += stats.evictions;
+        }
+        println!(
+            "  Total    {:<8} {:<8} {:<8} {:<8} {}",
+            total.reads, total.writes, total.hits, total.misses, total.evictions
+        );
+
+        let accesses = total.hits + total.misses;
+        if accesses > 0 {
+            println!(
+                "Hit rate: {:.2}%",
+                total.hits as f64 * 100.0 / accesses as f64
+            );
+        }
+        match self.write {
+            Write::BACK => println!("Writebacks: {}", self.writebacks),
+            Write::THROUGH => println!("Memory writes: {}", total.writes),
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{ensure, Context, Result};
+
+use crate::{
+    disas::{disassemble, Address, Cpu, Register, Stat},
+    exec::{dump_cpu, step},
+    hook::Hook,
+    load::ElfPhdr,
+    memory::Memory,
+    sym::SymTab,
+};
+
+/// The return address pushed for the called function. Returning to it ends the call.
+pub const SENTINEL: Address = Address::MAX;
+
+/// Registers that carry the first six arguments, in order.
+pub const ARG_REGS: [Register; 6] = [
+    Register::RDI,
+    Register::RSI,
+    Register::RDX,
+    Register::RCX,
+    Register::R8,
+    Register::R9,
+];
+
+/// Prepares `cpu` to call the function at `func`: `%rsp` starts at the top of the STACK
+/// segment with [`SENTINEL`] pushed as the return address, and the arguments are placed
+/// in the argument registers.
+pub fn setup(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    phdrs: &[ElfPhdr],
+    func: Address,
+    args: &[Address],
+) -> Result<()> {
+    ensure!(
+        args.len() <= ARG_REGS.len(),
+        "At most {} arguments can be passed in registers",
+        ARG_REGS.len()
+    );
+    let stack = phdrs
+        .iter()
+        .find(|p| p.ptype == 2)
+        .context("There is no STACK segment")?;
+
+    let top = stack
+        .vaddr
+        .checked_add(stack.size)
+        .context("The STACK segment extends past the end of the address space")?;
+    let rsp = (top as Address).wrapping_sub(8);
+    ensure!(
+        memory.store(rsp, SENTINEL),
+        "The top of the STACK segment at 0x{:x} is not writable",
+        rsp
+    );
+    cpu.reg[Register::RSP as usize] = rsp;
+    for (reg, val) in ARG_REGS.iter().zip(args) {
+        cpu.reg[*reg as usize] = *val;
+    }
+    cpu.pc = func;
+
+    Ok(())
+}
+
+/// Runs the called function until it returns to [`SENTINEL`] or stops. Returns the number
+/// of instructions executed. In trace mode every instruction and the resulting cpu state
+/// is printed.
+pub fn run_call(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    symbols: &SymTab,
+    trace: bool,
+) -> u64 {
+    let mut count = 0;
+
+    while cpu.stat == Stat::AOK && cpu.pc != SENTINEL {
+        let inst = match step(cpu, memory, hooks) {
+            Some(v) => v,
+            None => break,
+        };
+        count += 1;
+
+        if trace {
+            println!("\nExecuting: {}", disassemble(&inst, symbols));
+            dump_cpu(cpu);
+        }
+    }
+
+    count
+}
@@ -0,0 +1,151 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    disas::{Address, Cpu, Icode, Inst, Register, Stat, NUM_REGS},
+    hook::Hook,
+    sym::SymTab,
+};
+
+const CALLEE_SAVED: [Register; 5] = [
+    Register::RBX,
+    Register::RBP,
+    Register::R12,
+    Register::R13,
+    Register::R14,
+];
+
+/// Caller-saved registers other than %rax, which holds the return value.
+const CALLER_SAVED: [Register; 8] = [
+    Register::RCX,
+    Register::RDX,
+    Register::RSI,
+    Register::RDI,
+    Register::R8,
+    Register::R9,
+    Register::R10,
+    Register::R11,
+];
+
+struct Frame {
+    callee: Address,
+    call_pc: Address,
+    ret_addr: Address,
+    saved: [Address; CALLEE_SAVED.len()],
+}
+
+/// Checks the x86-64 calling convention at run time. Callee-saved registers must have the
+/// same values after a `ret` as at the matching `call`, and the caller must not read a
+/// caller-saved register after a call returns until it has written it again.
+pub struct CallConv {
+    entry: Address,
+    symbols: SymTab,
+    shadow: Vec<Frame>,
+    clobbered: [Option<Address>; NUM_REGS as usize], // the callee that clobbered each register
+    violations: BTreeMap<Address, BTreeSet<String>>,
+}
+
+impl CallConv {
+    pub fn new(entry: Address, symbols: &SymTab) -> CallConv {
+        CallConv {
+            entry,
+            symbols: symbols.clone(),
+            shadow: Vec::new(),
+            clobbered: [None; NUM_REGS as usize],
+            violations: BTreeMap::new(),
+        }
+    }
+
+    fn name(&self, addr: Address) -> String {
+        match self.symbols.name_at(addr) {
+            Some(name) => format!("{} (0x{:03x})", name, addr),
+            None if addr == self.entry => format!("_start (0x{:03x})", addr),
+            None => format!("0x{:03x}", addr),
+        }
+    }
+
+    /// The function currently executing.
+    fn current(&self) -> Address {
+        match self.shadow.last() {
+            Some(frame) => frame.callee,
+            None => self.entry,
+        }
+    }
+}
+
+impl Hook for CallConv {
+    fn after(&mut self, pc: Address, inst: &Inst, cpu: &Cpu) {
+        if matches!(cpu.stat, Stat::ADR | Stat::INS | Stat::DIV) {
+            return;
+        }
+
+        for reg in inst.reads() {
+            if let Some(callee) = self.clobbered[reg as usize].take() {
+                let msg = format!(
+                    "reads caller-saved %{} at 0x{:03x} after the call to {} without writing it",
+                    reg,
+                    pc,
+                    self.name(callee)
+                );
+                self.violations
+                    .entry(self.current())
+                    .or_default()
+                    .insert(msg);
+            }
+        }
+        for reg in inst.writes() {
+            self.clobbered[reg as usize] = None;
+        }
+
+        match inst.icode {
+            Icode::CALL => {
+                self.clobbered = [None; NUM_REGS as usize];
+                self.shadow.push(Frame {
+                    callee: cpu.pc,
+                    call_pc: pc,
+                    ret_addr: inst.val_p,
+                    saved: CALLEE_SAVED.map(|r| cpu.reg[r as usize]),
+                });
+            }
+            Icode::RET => {
+                let i = match self.shadow.iter().rposition(|f| f.ret_addr == cpu.pc) {
+                    Some(v) => v,
+                    None => return, // not a return from a call we saw
+                };
+                let frame = self.shadow.remove(i);
+                self.shadow.truncate(i);
+
+                for (reg, saved) in CALLEE_SAVED.iter().zip(frame.saved.iter()) {
+                    let val = cpu.reg[*reg as usize];
+                    if val != *saved {
+                        let msg = format!(
+                            "clobbers callee-saved %{} (0x{:x} at the call from 0x{:03x}, 0x{:x} at ret)",
+                            reg, saved, frame.call_pc, val
+                        );
+                        self.violations.entry(frame.callee).or_default().insert(msg);
+                    }
+                }
+
+                // the callee may have left anything in the caller-saved registers
+                for reg in CALLER_SAVED.iter() {
+                    self.clobbered[*reg as usize] = Some(frame.callee);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn report(&self) {
+        if self.violations.is_empty() {
+            println!("Calling convention: no violations");
+            return;
+        }
+
+        println!("Calling convention violations:");
+        for (f, msgs) in self.violations.iter() {
+            println!("  {}:", self.name(*f));
+            for msg in msgs.iter() {
+                println!("    {}", msg);
+            }
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use std::io::Cursor;
 
-use binrw::BinRead;
-use anyhow::{Result, ensure};
+use anyhow::{ensure, Result};
+use binrw::{BinRead, BinWrite};
 
-const MAGIC: u32 = 0x464c45;
+pub const MAGIC: u32 = 0x464c45;
 
 #[repr(C)]
-#[derive(BinRead)]
+#[derive(BinRead, BinWrite)]
 pub struct ElfHdr {
     pub version: u16,
     pub entry: u16,
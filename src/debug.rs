@@ -0,0 +1,447 @@
+use std::io::{self, BufRead, Write};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    disas::{disassemble, memtoi, Address, Cpu, Register, Stat},
+    exec::{dump_cpu, step},
+    hook::Hook,
+    memory::Memory,
+    parse_num,
+    sym::SymTab,
+};
+
+#[derive(Clone, Copy, PartialEq)]
+enum Op {
+    ADD,
+    SUB,
+    EQ,
+    NE,
+    LT,
+    LE,
+    GT,
+    GE,
+    AND,
+    OR,
+}
+
+enum Expr {
+    Num(u64),
+    Reg(Register),
+    PC,
+    ZF,
+    SF,
+    OF,
+    Mem(Box<Expr>),
+    Binary(Op, Box<Expr>, Box<Expr>),
+}
+
+/// A condition over the cpu state, e.g. `rax==5 && [rsp+8]!=0`. Registers are named as in
+/// the disassembly, with or without `%`, and `pc`, `zf`, `sf` and `of` are also available.
+/// `[addr]` reads the quad at `addr`. Comparisons are signed and `&&`, `||`, `+`, `-` and
+/// parentheses are supported; a bare value is true when it is nonzero.
+pub struct Condition {
+    text: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(text: &str) -> Result<Condition> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.or()?;
+        ensure!(
+            parser.pos == parser.tokens.len(),
+            "Unexpected '{}' in condition: {}",
+            parser.tokens[parser.pos],
+            text
+        );
+
+        Ok(Condition {
+            text: text.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Evaluates the condition. A memory read outside of RAM makes it false.
+    pub fn eval(&self, cpu: &Cpu, memory: &Memory) -> bool {
+        eval(&self.expr, cpu, memory).is_some_and(|v| v != 0)
+    }
+}
+
+fn eval(expr: &Expr, cpu: &Cpu, memory: &Memory) -> Option<u64> {
+    let val = match expr {
+        Expr::Num(n) => *n,
+        Expr::Reg(reg) => cpu.reg[*reg as usize],
+        Expr::PC => cpu.pc,
+        Expr::ZF => cpu.zf as u64,
+        Expr::SF => cpu.sf as u64,
+        Expr::OF => cpu.of as u64,
+        Expr::Mem(addr) => memtoi(&memory.ram, eval(addr, cpu, memory)?)?,
+        Expr::Binary(op, lhs, rhs) => {
+            let a = eval(lhs, cpu, memory)?;
+            let b = eval(rhs, cpu, memory)?;
+            let (sa, sb) = (a as i64, b as i64);
+            match op {
+                Op::ADD => a.wrapping_add(b),
+                Op::SUB => a.wrapping_sub(b),
+                Op::EQ => (a == b) as u64,
+                Op::NE => (a != b) as u64,
+                Op::LT => (sa < sb) as u64,
+                Op::LE => (sa <= sb) as u64,
+                Op::GT => (sa > sb) as u64,
+                Op::GE => (sa >= sb) as u64,
+                Op::AND => (a != 0 && b != 0) as u64,
+                Op::OR => (a != 0 || b != 0) as u64,
+            }
+        }
+    };
+
+    Some(val)
+}
+
+fn tokenize(text: &str) -> Result<Vec<String>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        if c.is_whitespace() {
+            i += 1;
+        } else if c.is_alphanumeric() || c == '%' || c == '_' {
+            let start = i;
+            i += 1;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            tokens.push(chars[start..i].iter().collect());
+        } else {
+            let pair: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            let len = match pair.as_str() {
+                "==" | "!=" | "<=" | ">=" | "&&" | "||" => 2,
+                _ if "+-<>[]()".contains(c) => 1,
+                _ => bail!("Unexpected '{}' in condition: {}", c, text),
+            };
+            tokens.push(chars[i..i + len].iter().collect());
+            i += len;
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<String>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.pos).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String> {
+        let token = self.tokens.get(self.pos).context("Incomplete condition")?;
+        self.pos += 1;
+        Ok(token.clone())
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        let next = self.next()?;
+        ensure!(next == token, "Expected '{}' but found '{}'", token, next);
+        Ok(())
+    }
+
+    /// Parses a left-associative chain of `ops` over operands parsed by `operand`.
+    fn chain(
+        &mut self,
+        ops: &[(&str, Op)],
+        operand: fn(&mut Parser) -> Result<Expr>,
+    ) -> Result<Expr> {
+        let mut lhs = operand(self)?;
+        while let Some(op) = self
+            .peek()
+            .and_then(|t| ops.iter().find(|(s, _)| *s == t).map(|(_, op)| *op))
+        {
+            self.pos += 1;
+            let rhs = operand(self)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+
+        Ok(lhs)
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        self.chain(&[("||", Op::OR)], Parser::and)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        self.chain(&[("&&", Op::AND)], Parser::compare)
+    }
+
+    fn compare(&mut self) -> Result<Expr> {
+        let ops = [
+            ("==", Op::EQ),
+            ("!=", Op::NE),
+            ("<", Op::LT),
+            ("<=", Op::LE),
+            (">", Op::GT),
+            (">=", Op::GE),
+        ];
+        self.chain(&ops, Parser::sum)
+    }
+
+    fn sum(&mut self) -> Result<Expr> {
+        self.chain(&[("+", Op::ADD), ("-", Op::SUB)], Parser::atom)
+    }
+
+    fn atom(&mut self) -> Result<Expr> {
+        let token = self.next()?;
+        let expr = match token.as_str() {
+            "(" => {
+                let expr = self.or()?;
+                self.expect(")")?;
+                expr
+            }
+            "[" => {
+                let addr = self.sum()?;
+                self.expect("]")?;
+                Expr::Mem(Box::new(addr))
+            }
+            "-" => Expr::Binary(Op::SUB, Box::new(Expr::Num(0)), Box::new(self.atom()?)),
+            _ => match token.to_lowercase().as_str() {
+                "pc" => Expr::PC,
+                "zf" => Expr::ZF,
+                "sf" => Expr::SF,
+                "of" => Expr::OF,
+                name => match Register::from_name(name) {
+                    Some(reg) => Expr::Reg(reg),
+                    None => Expr::Num(
+                        parse_num(&token)
+                            .with_context(|| format!("Unknown name in condition: {}", token))?,
+                    ),
+                },
+            },
+        };
+
+        Ok(expr)
+    }
+}
+
+/// Logs every change to a range of memory.
+struct Watch {
+    addr: Address,
+    len: usize,
+    old: Vec<u8>,
+}
+
+impl Watch {
+    fn bytes(&self, memory: &Memory) -> Vec<u8> {
+        let start = (self.addr as usize).min(memory.ram.len());
+        let end = (start + self.len).min(memory.ram.len());
+        memory.ram[start..end].to_vec()
+    }
+
+    fn format(&self, bytes: &[u8]) -> String {
+        if bytes.len() == 8 {
+            format!("0x{:016x}", memtoi(bytes, 0).unwrap_or(0))
+        } else {
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+            hex.join(" ")
+        }
+    }
+}
+
+/// Runs a program under breakpoints and watchpoints. Address breakpoints stop before the
+/// instruction at that address executes and conditional breakpoints stop when their
+/// condition becomes true. At a stop the cpu state is shown and execution pauses until a
+/// command is read from stdin; at end of input it carries on, so breakpoints only log.
+/// Watchpoints never pause and log every change to the watched bytes.
+#[derive(Default)]
+pub struct Debugger {
+    addrs: Vec<Address>,
+    conds: Vec<(Condition, bool)>, // condition and whether it held last time
+    watches: Vec<Watch>,
+    stepping: bool,
+    interactive: bool,
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            interactive: true,
+            ..Default::default()
+        }
+    }
+
+    /// Adds a breakpoint at an address or symbol.
+    pub fn break_at(&mut self, spec: &str, symbols: &SymTab) -> Result<()> {
+        let addr = match symbols.addr_of(spec.trim()) {
+            Some(v) => v,
+            None => parse_num(spec).with_context(|| format!("Unknown breakpoint: {}", spec))?,
+        };
+        self.addrs.push(addr);
+        Ok(())
+    }
+
+    pub fn break_if(&mut self, spec: &str) -> Result<()> {
+        self.conds.push((Condition::parse(spec)?, false));
+        Ok(())
+    }
+
+    /// Adds a watchpoint given as `ADDR[:LEN]`, watching a quad by default.
+    pub fn watch(&mut self, spec: &str, memory: &Memory) -> Result<()> {
+        let (addr, len) = match spec.split_once(':') {
+            Some((addr, len)) => (parse_num(addr)?, parse_num(len)? as usize),
+            None => (parse_num(spec)?, 8),
+        };
+        ensure!(len > 0, "Empty watchpoint: {}", spec);
+        ensure!(
+            (addr as usize)
+                .checked_add(len)
+                .is_some_and(|end| end <= memory.ram.len()),
+            "Watchpoint outside of memory: {}",
+            spec
+        );
+
+        let mut watch = Watch {
+            addr,
+            len,
+            old: Vec::new(),
+        };
+        watch.old = watch.bytes(memory);
+        self.watches.push(watch);
+        Ok(())
+    }
+
+    /// Like `exec::execute`, but stops at breakpoints and logs watched memory.
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+        symbols: &SymTab,
+        trace: bool,
+    ) -> u64 {
+        let mut count = 0;
+        let mut resumed_at = None;
+
+        while cpu.stat == Stat::AOK {
+            let mut reasons = self.triggered(cpu, memory, symbols);
+            if self.addrs.contains(&cpu.pc) && resumed_at != Some(cpu.pc) {
+                reasons.insert(0, format!("Breakpoint at {}", name(cpu.pc, symbols)));
+            }
+            if self.stepping && reasons.is_empty() {
+                reasons.push(format!("Stepped to {}", name(cpu.pc, symbols)));
+            }
+            if !reasons.is_empty() {
+                for reason in reasons.iter() {
+                    println!("{}", reason);
+                }
+                if !self.pause(cpu) {
+                    break;
+                }
+                resumed_at = Some(cpu.pc);
+            } else {
+                resumed_at = None;
+            }
+
+            let pc = cpu.pc;
+            let inst = match step(cpu, memory, hooks) {
+                Some(v) => v,
+                None => break,
+            };
+            count += 1;
+
+            if trace {
+                println!("\nExecuting: {}", disassemble(&inst, symbols));
+                dump_cpu(cpu);
+            }
+            self.log_watches(pc, memory, symbols);
+        }
+
+        // conditions that became true on the last instruction
+        for reason in self.triggered(cpu, memory, symbols) {
+            println!("{}", reason);
+        }
+
+        count
+    }
+
+    /// Conditions that have become true since they were last checked.
+    fn triggered(&mut self, cpu: &Cpu, memory: &Memory, symbols: &SymTab) -> Vec<String> {
+        let mut reasons = Vec::new();
+        for (cond, held) in self.conds.iter_mut() {
+            let holds = cond.eval(cpu, memory);
+            if holds && !*held {
+                reasons.push(format!(
+                    "Condition {} holds at {}",
+                    cond.text,
+                    name(cpu.pc, symbols)
+                ));
+            }
+            *held = holds;
+        }
+
+        reasons
+    }
+
+    fn log_watches(&mut self, pc: Address, memory: &Memory, symbols: &SymTab) {
+        for watch in self.watches.iter_mut() {
+            let new = watch.bytes(memory);
+            if new != watch.old {
+                println!(
+                    "Watchpoint 0x{:04x}:{} written by {}: {} -> {}",
+                    watch.addr,
+                    watch.len,
+                    name(pc, symbols),
+                    watch.format(&watch.old),
+                    watch.format(&new)
+                );
+                watch.old = new;
+            }
+        }
+    }
+
+    /// Shows the cpu and waits for a command. Returns false to stop execution.
+    fn pause(&mut self, cpu: &Cpu) -> bool {
+        dump_cpu(cpu);
+        self.stepping = false;
+        if !self.interactive {
+            return true;
+        }
+
+        loop {
+            print!("[c]ontinue, [s]tep or [q]uit> ");
+            io::stdout().flush().ok();
+
+            let mut line = String::new();
+            match io::stdin().lock().read_line(&mut line) {
+                Ok(0) | Err(_) => {
+                    println!();
+                    self.interactive = false;
+                    return true;
+                }
+                Ok(_) => (),
+            }
+            match line.trim() {
+                "" | "c" => return true,
+                "s" => {
+                    self.stepping = true;
+                    return true;
+                }
+                "q" => return false,
+                _ => (),
+            }
+        }
+    }
+}
+
+fn name(addr: Address, symbols: &SymTab) -> String {
+    match symbols.lookup(addr) {
+        Some((sym, 0)) => format!("0x{:03x} <{}>", addr, sym),
+        Some((sym, off)) => format!("0x{:03x} <{}+0x{:x}>", addr, sym, off),
+        None => format!("0x{:03x}", addr),
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{disas::Address, journal::Journal, parse_num};
+
+/// A memory-mapped device. Offsets are relative to the start of the mapped region and
+/// accesses are always whole quads.
+pub trait Device {
+    fn name(&self) -> &str;
+
+    /// Size of the mapped region in bytes.
+    fn size(&self) -> Address;
+
+    /// Reads a quad. Input from outside the simulation must be taken through `io` so it
+    /// can be recorded and replayed.
+    fn read(&mut self, offset: Address, io: &mut Journal) -> Address;
+
+    /// Writes a quad. Output meant for the console goes through `io`.
+    fn write(&mut self, offset: Address, val: Address, io: &mut Journal);
+
+    /// Called once for every instruction executed.
+    fn tick(&mut self) {}
+
+    /// Polled after an instruction when an interrupt could be taken. Returning true raises
+    /// the interrupt and acknowledges it.
+    fn interrupt(&mut self) -> bool {
+        false
+    }
+
+    /// Called when execution stops.
+    fn halt(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Console UART. Reading returns the next byte of stdin, or all ones at end of input.
+/// Writing prints the low byte as a char.
+pub struct Uart;
+
+impl Device for Uart {
+    fn name(&self) -> &str {
+        "uart"
+    }
+
+    fn size(&self) -> Address {
+        8
+    }
+
+    fn read(&mut self, _offset: Address, io: &mut Journal) -> Address {
+        io.input("uart", |console| {
+            let mut byte = [0u8; 1];
+            match console.read(&mut byte) {
+                Ok(1) => byte[0] as Address,
+                _ => Address::MAX,
+            }
+        })
+    }
+
+    fn write(&mut self, _offset: Address, val: Address, io: &mut Journal) {
+        io.output(&(val as u8 as char).to_string());
+    }
+}
+
+/// Counts executed instructions. Writing sets the count. With a period, an interrupt is
+/// raised every time the count reaches a multiple of it.
+#[derive(Default)]
+pub struct Timer {
+    count: Address,
+    period: Address,
+    pending: bool,
+}
+
+impl Device for Timer {
+    fn name(&self) -> &str {
+        "timer"
+    }
+
+    fn size(&self) -> Address {
+        8
+    }
+
+    fn read(&mut self, _offset: Address, _io: &mut Journal) -> Address {
+        self.count
+    }
+
+    fn write(&mut self, _offset: Address, val: Address, _io: &mut Journal) {
+        self.count = val;
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.period != 0 && self.count.is_multiple_of(self.period) {
+            self.pending = true;
+        }
+    }
+
+    fn interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.pending)
+    }
+}
+
+/// Framebuffer with one RGB332 byte per pixel, written to a PPM file at halt.
+pub struct Framebuffer {
+    path: String,
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    pub fn new(path: &str, width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            path: path.to_string(),
+            width,
+            height,
+            pixels: vec![0; width * height],
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn name(&self) -> &str {
+        "fb"
+    }
+
+    fn size(&self) -> Address {
+        self.pixels.len() as Address
+    }
+
+    fn read(&mut self, offset: Address, _io: &mut Journal) -> Address {
+        let mut bytes = [0u8; 8];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = *self.pixels.get(offset as usize + i).unwrap_or(&0);
+        }
+        Address::from_le_bytes(bytes)
+    }
+
+    fn write(&mut self, offset: Address, val: Address, _io: &mut Journal) {
+        for (i, byte) in val.to_le_bytes().iter().enumerate() {
+            if let Some(pixel) = self.pixels.get_mut(offset as usize + i) {
+                *pixel = *byte;
+            }
+        }
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        let file = File::create(&self.path).context(format!("Failed to create {}", self.path))?;
+        let mut out = BufWriter::new(file);
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        for pixel in self.pixels.iter() {
+            // expand 3-3-2 bits to 8 bits per channel
+            let r = (*pixel as u16 >> 5) * 255 / 7;
+            let g = (*pixel as u16 >> 2 & 0x7) * 255 / 7;
+            let b = (*pixel as u16 & 0x3) * 255 / 3;
+            out.write_all(&[r as u8, g as u8, b as u8])?;
+        }
+        out.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Parses a `--device` spec of the form `uart`, `timer[=PERIOD]` or `fb=FILE[:WxH]`,
+/// optionally followed by `@ADDR`. Returns the base address and the device.
+pub fn parse_device(spec: &str) -> Result<(Address, Box<dyn Device>)> {
+    let (spec, addr) = match spec.split_once('@') {
+        Some((spec, addr)) => (spec, Some(parse_num(addr)?)),
+        None => (spec, None),
+    };
+    let (kind, arg) = match spec.split_once('=') {
+        Some((kind, arg)) => (kind, Some(arg)),
+        None => (spec, None),
+    };
+
+    let (default, device): (Address, Box<dyn Device>) = match (kind, arg) {
+        ("uart", None) => (0xff0, Box::new(Uart)),
+        ("timer", None) => (0xff8, Box::new(Timer::default())),
+        ("timer", Some(arg)) => {
+            let timer = Timer {
+                period: parse_num(arg)?,
+                ..Default::default()
+            };
+            (0xff8, Box::new(timer))
+        }
+        ("fb", Some(arg)) => {
+            let (path, width, height) = match arg.split_once(':') {
+                Some((path, dims)) => {
+                    let (w, h) = dims.split_once('x').context("Expected WxH")?;
+                    (path, w.parse()?, h.parse()?)
+                }
+                None => (arg, 32, 32),
+            };
+            (0xb00, Box::new(Framebuffer::new(path, width, height)))
+        }
+        _ => bail!("Unknown device: {}", spec),
+    };
+
+    Ok((addr.unwrap_or(default), device))
+}
@@ -1,30 +1,93 @@
-use std::process::exit;
+use std::{fmt, process::exit, rc::Rc};
 
-use crate::{check::ElfHdr, load::ElfPhdr};
+use clap::ValueEnum;
 
-const NUM_REGS: u8 = 15;
+use crate::{
+    check::ElfHdr,
+    isa::{Extension, Field, Isa},
+    load::ElfPhdr,
+    sym::SymTab,
+};
 
-type Address = u64;
+pub const NUM_REGS: u8 = 15;
+
+pub type Address = u64;
 type Flag = bool;
 
-enum Stat {
+/// Flag bits in the x86 eflags layout, used wherever the flags are saved as one word.
+pub const ZF_BIT: Address = 1 << 6;
+pub const SF_BIT: Address = 1 << 7;
+pub const IF_BIT: Address = 1 << 9;
+pub const OF_BIT: Address = 1 << 11;
+
+#[derive(PartialEq, Clone, Copy)]
+pub enum Stat {
     AOK,
     HLT,
     ADR,
     INS,
+    DIV,
+}
+
+impl fmt::Display for Stat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stat::AOK => write!(f, "AOK"),
+            Stat::HLT => write!(f, "HLT"),
+            Stat::ADR => write!(f, "ADR"),
+            Stat::INS => write!(f, "INS"),
+            Stat::DIV => write!(f, "DIV"),
+        }
+    }
 }
 
 pub struct Cpu {
-    reg: [Address; NUM_REGS as usize],
-    zf: Flag,
-    sf: Flag,
-    of: Flag,
-    pc: Address,
-    stat: Stat,
+    pub reg: [Address; NUM_REGS as usize],
+    pub zf: Flag,
+    pub sf: Flag,
+    pub of: Flag,
+    pub pc: Address,
+    pub stat: Stat,
+    pub isa: Isa,
+    /// Whether device interrupts are delivered
+    pub ie: Flag,
+    /// Address of the exception and interrupt vector table, if any
+    pub vectors: Option<Address>,
+}
+
+impl Cpu {
+    pub fn new(pc: Address) -> Cpu {
+        Cpu {
+            reg: [0u64; NUM_REGS as usize],
+            zf: false,
+            sf: false,
+            of: false,
+            pc,
+            stat: Stat::AOK,
+            isa: Isa::new(&[]),
+            ie: true,
+            vectors: None,
+        }
+    }
+
+    /// The condition codes and interrupt enable flag packed as eflags.
+    pub fn flags(&self) -> Address {
+        (self.zf as Address * ZF_BIT)
+            | (self.sf as Address * SF_BIT)
+            | (self.ie as Address * IF_BIT)
+            | (self.of as Address * OF_BIT)
+    }
+
+    pub fn set_flags(&mut self, val: Address) {
+        self.zf = val & ZF_BIT != 0;
+        self.sf = val & SF_BIT != 0;
+        self.ie = val & IF_BIT != 0;
+        self.of = val & OF_BIT != 0;
+    }
 }
 
 #[derive(PartialEq)]
-enum Icode {
+pub enum Icode {
     HALT,
     NOP,
     CMOV,
@@ -37,11 +100,18 @@ enum Icode {
     RET,
     PUSHQ,
     POPQ,
+    IADDQ,
+    LEAVE,
+    SYSCALL,
+    IRET,
+    CAS,
+    /// An instruction of a set registered outside this crate, with its icode
+    EXT(u8),
     INVALID,
 }
 
 impl Icode {
-    fn from(val: u8) -> Icode {
+    pub fn from(val: u8) -> Icode {
         match val {
             0 => Icode::HALT,
             1 => Icode::NOP,
@@ -55,13 +125,16 @@ impl Icode {
             9 => Icode::RET,
             10 => Icode::PUSHQ,
             11 => Icode::POPQ,
+            12 => Icode::IADDQ,
+            13 => Icode::LEAVE,
+            14 => Icode::SYSCALL,
             _ => Icode::INVALID,
         }
     }
 }
 
-#[derive(PartialEq)]
-enum Register {
+#[derive(PartialEq, Clone, Copy)]
+pub enum Register {
     RAX,
     RCX,
     RDX,
@@ -104,6 +177,27 @@ impl Register {
         true
     }
 
+    /// Decodes a register id; 0xF and any other id without a register is NOREG.
+    pub fn from(val: u8) -> Register {
+        let mut reg = Register::NOREG;
+        reg.set(val);
+        reg
+    }
+
+    /// Looks up a register by its name, with or without the leading `%`.
+    pub fn from_name(name: &str) -> Option<Register> {
+        let name = name.strip_prefix('%').unwrap_or(name);
+        let mut reg = Register::NOREG;
+        for i in 0..NUM_REGS {
+            reg.set(i);
+            if reg.to_string() == name {
+                return Some(reg);
+            }
+        }
+
+        None
+    }
+
     fn set_f(&mut self, val: u8) -> bool {
         if val != 15 {
             return false;
@@ -125,38 +219,96 @@ impl Register {
         true
     }
 
-    fn to_string(&self) -> String {
-        match self {
-            Register::RAX => String::from("rax"),
-            Register::RCX => String::from("rcx"),
-            Register::RDX => String::from("rdx"),
-            Register::RBX => String::from("rbx"),
-            Register::RSP => String::from("rsp"),
-            Register::RBP => String::from("rbp"),
-            Register::RSI => String::from("rsi"),
-            Register::RDI => String::from("rdi"),
-            Register::R8 => String::from("r8"),
-            Register::R9 => String::from("r9"),
-            Register::R10 => String::from("r10"),
-            Register::R11 => String::from("r11"),
-            Register::R12 => String::from("r12"),
-            Register::R13 => String::from("r13"),
-            Register::R14 => String::from("r14"),
-            Register::NOREG => String::from(""),
+    fn set_field(&mut self, field: Field, val: u8) -> bool {
+        match field {
+            Field::Reg => self.set(val),
+            Field::NoReg => self.set_f(val),
+            Field::Any => self.set_a(val),
         }
     }
 }
 
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Register::RAX => "rax",
+            Register::RCX => "rcx",
+            Register::RDX => "rdx",
+            Register::RBX => "rbx",
+            Register::RSP => "rsp",
+            Register::RBP => "rbp",
+            Register::RSI => "rsi",
+            Register::RDI => "rdi",
+            Register::R8 => "r8",
+            Register::R9 => "r9",
+            Register::R10 => "r10",
+            Register::R11 => "r11",
+            Register::R12 => "r12",
+            Register::R13 => "r13",
+            Register::R14 => "r14",
+            Register::NOREG => "",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 pub struct Inst {
-    icode: Icode,
-    ifun: u8,
-    ra: Register,
-    rb: Register,
-    val_c: Option<Address>,
-    val_p: Address,
+    pub icode: Icode,
+    pub ifun: u8,
+    pub ra: Register,
+    pub rb: Register,
+    pub val_c: Option<Address>,
+    pub val_p: Address,
+    /// The instruction set that decoded the instruction, which renders and executes it
+    pub set: Option<Rc<dyn Extension>>,
 }
 
-enum Cmov {
+impl Inst {
+    /// Registers whose values the instruction uses. `xorq` and `subq` of a register with
+    /// itself do not depend on its value.
+    pub fn reads(&self) -> Vec<Register> {
+        let regs = match self.icode {
+            Icode::CMOV => vec![self.ra],
+            Icode::RMMOVQ => vec![self.ra, self.rb],
+            Icode::MRMOVQ => vec![self.rb],
+            Icode::OPQ => match Opq::from(self.ifun) {
+                Opq::XOR | Opq::SUB if self.ra == self.rb => vec![],
+                _ => vec![self.ra, self.rb],
+            },
+            Icode::PUSHQ => vec![self.ra, Register::RSP],
+            Icode::POPQ | Icode::CALL | Icode::RET | Icode::IRET => vec![Register::RSP],
+            Icode::IADDQ => vec![self.rb],
+            Icode::LEAVE => vec![Register::RBP],
+            Icode::CAS => vec![self.ra, self.rb, Register::RAX],
+            Icode::SYSCALL => match Sys::from(self.ifun) {
+                Sys::WRCH | Sys::WRINT => vec![Register::RDI],
+                _ => vec![],
+            },
+            _ => vec![],
+        };
+
+        regs.into_iter().filter(|r| *r != Register::NOREG).collect()
+    }
+
+    /// Registers the instruction may write.
+    pub fn writes(&self) -> Vec<Register> {
+        match self.icode {
+            Icode::CMOV | Icode::IRMOVQ | Icode::OPQ | Icode::IADDQ => vec![self.rb],
+            Icode::MRMOVQ => vec![self.ra],
+            Icode::PUSHQ | Icode::CALL | Icode::RET | Icode::IRET => vec![Register::RSP],
+            Icode::POPQ => vec![Register::RSP, self.ra],
+            Icode::LEAVE => vec![Register::RSP, Register::RBP],
+            Icode::CAS => vec![Register::RAX],
+            Icode::SYSCALL => match Sys::from(self.ifun) {
+                Sys::RDINT => vec![Register::RAX],
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+}
+
+pub enum Cmov {
     RRMOVQ,
     CMOVLE,
     CMOVL,
@@ -180,25 +332,39 @@ impl Cmov {
     }
 }
 
-enum Opq {
+pub enum Opq {
     ADD,
     SUB,
     AND,
     XOR,
+    MUL,
+    DIV,
+    MOD,
+    SHL,
+    SAR,
+    SHR,
+    OR,
 }
 
 impl Opq {
-    fn from(val: u8) -> Opq {
+    pub fn from(val: u8) -> Opq {
         match val {
             0 => Opq::ADD,
             1 => Opq::SUB,
             2 => Opq::AND,
-            _ => Opq::XOR,
+            3 => Opq::XOR,
+            4 => Opq::MUL,
+            5 => Opq::DIV,
+            6 => Opq::MOD,
+            7 => Opq::SHL,
+            8 => Opq::SAR,
+            9 => Opq::SHR,
+            _ => Opq::OR,
         }
     }
 }
 
-enum Jump {
+pub enum Jump {
     JMP,
     JLE,
     JL,
@@ -209,7 +375,7 @@ enum Jump {
 }
 
 impl Jump {
-    fn from(val: u8) -> Jump {
+    pub fn from(val: u8) -> Jump {
         match val {
             0 => Jump::JMP,
             1 => Jump::JLE,
@@ -222,14 +388,50 @@ impl Jump {
     }
 }
 
-pub fn fetch(cpu: &mut Cpu, memory: &Box<[u8]>) -> Inst {
+pub enum Sys {
+    WRCH,
+    WRINT,
+    RDINT,
+    EXIT,
+}
+
+impl Sys {
+    pub fn from(val: u8) -> Sys {
+        match val {
+            0 => Sys::WRCH,
+            1 => Sys::WRINT,
+            2 => Sys::RDINT,
+            _ => Sys::EXIT,
+        }
+    }
+}
+
+/// Optional instruction set extensions, enabled with `--ext`.
+#[derive(ValueEnum, PartialEq, Clone, Copy, Debug)]
+pub enum Ext {
+    /// Console I/O and exit system calls (icode E)
+    SYSCALL,
+    /// Add an immediate to a register (icode C)
+    IADDQ,
+    /// Restore the caller's stack frame (icode D)
+    LEAVE,
+    /// Multiply, divide, modulo, shift and or operations (OPq ifun 4-A)
+    ALU,
+    /// Return from an exception or interrupt handler (F0)
+    IRET,
+    /// Atomic compare-and-swap of %rax with a memory quad (F1)
+    CAS,
+}
+
+pub fn fetch(cpu: &mut Cpu, memory: &[u8]) -> Inst {
     let mut inst = Inst {
         icode: Icode::INVALID,
         ifun: 0,
         ra: Register::NOREG,
         rb: Register::NOREG,
         val_c: None,
-        val_p: 0,
+        val_p: cpu.pc,
+        set: None,
     };
 
     let b0 = match memory.get(cpu.pc as usize) {
@@ -239,26 +441,22 @@ pub fn fetch(cpu: &mut Cpu, memory: &Box<[u8]>) -> Inst {
             return inst;
         }
     };
-    inst.icode = Icode::from(b0 >> 4);
     inst.ifun = b0 & 0xF;
 
-    // set valP
-    inst.val_p = cpu.pc;
-    match inst.icode {
-        Icode::HALT | Icode::NOP | Icode::RET => inst.val_p += 1,
-        Icode::CMOV | Icode::OPQ | Icode::PUSHQ | Icode::POPQ => inst.val_p += 2,
-        Icode::JUMP | Icode::CALL => inst.val_p += 9,
-        Icode::IRMOVQ | Icode::RMMOVQ | Icode::MRMOVQ => inst.val_p += 10,
-        Icode::INVALID => {
+    // find the instruction set claiming the icode and ifun and set valP
+    let (set, enc) = match cpu.isa.lookup(b0 >> 4, inst.ifun) {
+        Some(v) => v,
+        None => {
             inst.ifun = *b0;
             inv_inst(&mut inst, cpu);
             return inst;
         }
-    }
+    };
+    inst.val_p += enc.length();
 
     // set second byte if required
     let (mut b1_h, mut b1_l): (u8, u8) = (0, 0);
-    if inst.val_p - cpu.pc > 1 {
+    if enc.regids.is_some() {
         let b1 = match memory.get((cpu.pc + 1) as usize) {
             Some(v) => v,
             None => {
@@ -270,155 +468,143 @@ pub fn fetch(cpu: &mut Cpu, memory: &Box<[u8]>) -> Inst {
         b1_l = b1 & 0xF;
     }
 
-    // check and set every instruction
-    match inst.icode {
-        Icode::HALT => {
-            if inst.ifun != 0 {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-            cpu.stat = Stat::HLT;
-        }
-        Icode::NOP | Icode::RET => {
-            if inst.ifun != 0 {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::CMOV => {
-            if inst.ifun > 6 || !inst.ra.set(b1_h) || !inst.rb.set(b1_l) {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::IRMOVQ => {
-            inst.val_c = memtoi(memory, cpu.pc + 2);
-            if inst.ifun != 0 || !inst.ra.set_f(b1_h) || !inst.rb.set(b1_l) || inst.val_c.is_none()
-            {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::RMMOVQ | Icode::MRMOVQ => {
-            inst.val_c = memtoi(memory, cpu.pc + 2);
-            if inst.ifun != 0 || !inst.ra.set(b1_h) || !inst.rb.set_a(b1_l) || inst.val_c.is_none()
-            {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::OPQ => {
-            if inst.ifun > 3 || !inst.ra.set(b1_h) || !inst.rb.set(b1_l) {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::JUMP => {
-            inst.val_c = memtoi(memory, cpu.pc + 1);
-            if inst.ifun > 6 || inst.val_c.is_none() {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::CALL => {
-            inst.val_c = memtoi(memory, cpu.pc + 1);
-            if inst.ifun != 0 || inst.val_c.is_none() {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::PUSHQ | Icode::POPQ => {
-            if inst.ifun != 0 || !inst.ra.set(b1_h) || !inst.rb.set_f(b1_l) {
-                inv_inst(&mut inst, cpu);
-                return inst;
-            }
-        }
-        Icode::INVALID => (), // impossible
+    // check the register fields and constant against the encoding
+    let regs_valid = match enc.regids {
+        Some((a, b)) => inst.ra.set_field(a, b1_h) && inst.rb.set_field(b, b1_l),
+        None => true,
+    };
+    if enc.val_c {
+        inst.val_c = memtoi(memory, cpu.pc + enc.val_c_at());
+    }
+    if !regs_valid || (enc.val_c && inst.val_c.is_none()) {
+        inv_inst(&mut inst, cpu);
+        return inst;
+    }
+
+    inst.icode = set.icode(b0 >> 4);
+    if inst.icode == Icode::HALT {
+        cpu.stat = Stat::HLT;
     }
+    inst.set = Some(set);
 
     inst
 }
 
-pub fn disassemble(inst: &Inst) {
+/// Renders an instruction in assembly syntax using the instruction set that decoded it.
+pub fn disassemble(inst: &Inst, symbols: &SymTab) -> String {
+    match &inst.set {
+        Some(set) => set.render(inst, symbols),
+        None => String::new(),
+    }
+}
+
+/// Renders one of the built-in instructions. Call and jump targets and `irmovq`
+/// immediates that match a symbol are shown by name, with the raw value in a comment.
+pub fn render_builtin(inst: &Inst, symbols: &SymTab) -> String {
     match inst.icode {
-        Icode::HALT => print!("halt"),
-        Icode::NOP => print!("nop"),
+        Icode::HALT => String::from("halt"),
+        Icode::NOP => String::from("nop"),
         Icode::CMOV => {
-            match Cmov::from(inst.ifun) {
-                Cmov::RRMOVQ => print!("rrmovq "),
-                Cmov::CMOVLE => print!("cmovle "),
-                Cmov::CMOVL => print!("cmovl "),
-                Cmov::CMOVE => print!("cmove "),
-                Cmov::CMOVNE => print!("cmovne "),
-                Cmov::CMOVGE => print!("cmovge "),
-                Cmov::CMOVG => print!("cmovg "),
+            let name = match Cmov::from(inst.ifun) {
+                Cmov::RRMOVQ => "rrmovq",
+                Cmov::CMOVLE => "cmovle",
+                Cmov::CMOVL => "cmovl",
+                Cmov::CMOVE => "cmove",
+                Cmov::CMOVNE => "cmovne",
+                Cmov::CMOVGE => "cmovge",
+                Cmov::CMOVG => "cmovg",
+            };
+
+            format!("{} %{}, %{}", name, inst.ra, inst.rb)
+        }
+        Icode::IRMOVQ => {
+            let val_c = inst.val_c.unwrap();
+            match symbols.name_at(val_c) {
+                Some(name) => format!("irmovq {}, %{}  # 0x{:x}", name, inst.rb, val_c),
+                None => format!("irmovq 0x{:x}, %{}", val_c, inst.rb),
             }
-
-            print!("%{}, %{}", inst.ra.to_string(), inst.rb.to_string());
         }
-        Icode::IRMOVQ => print!(
-            "irmovq 0x{:x}, %{}",
-            inst.val_c.unwrap(),
-            inst.rb.to_string()
-        ),
+        Icode::IADDQ => format!("iaddq 0x{:x}, %{}", inst.val_c.unwrap(), inst.rb),
+        Icode::LEAVE => String::from("leave"),
         Icode::RMMOVQ => {
-            print!(
-                "rmmovq %{}, 0x{:x}",
-                inst.ra.to_string(),
-                inst.val_c.unwrap()
-            );
+            let mut s = format!("rmmovq %{}, 0x{:x}", inst.ra, inst.val_c.unwrap());
             if inst.rb != Register::NOREG {
-                print!("(%{})", inst.rb.to_string());
+                s += &format!("(%{})", inst.rb);
             }
+            s
         }
         Icode::MRMOVQ => {
-            print!("mrmovq 0x{:x}", inst.val_c.unwrap());
+            let mut s = format!("mrmovq 0x{:x}", inst.val_c.unwrap());
             if inst.rb != Register::NOREG {
-                print!("(%{})", inst.rb.to_string());
+                s += &format!("(%{})", inst.rb);
             }
-            print!(", %{}", inst.ra.to_string());
+            s + &format!(", %{}", inst.ra)
         }
         Icode::OPQ => {
-            match Opq::from(inst.ifun) {
-                Opq::ADD => print!("add"),
-                Opq::SUB => print!("sub"),
-                Opq::AND => print!("and"),
-                Opq::XOR => print!("xor"),
-            }
-
-            print!("q %{}, %{}", inst.ra.to_string(), inst.rb.to_string());
+            let name = match Opq::from(inst.ifun) {
+                Opq::ADD => "add",
+                Opq::SUB => "sub",
+                Opq::AND => "and",
+                Opq::XOR => "xor",
+                Opq::MUL => "mul",
+                Opq::DIV => "div",
+                Opq::MOD => "mod",
+                Opq::SHL => "shl",
+                Opq::SAR => "sar",
+                Opq::SHR => "shr",
+                Opq::OR => "or",
+            };
+
+            format!("{}q %{}, %{}", name, inst.ra, inst.rb)
         }
         Icode::JUMP => {
-            match Jump::from(inst.ifun) {
-                Jump::JMP => print!("jmp"),
-                Jump::JLE => print!("jle"),
-                Jump::JL => print!("jl"),
-                Jump::JE => print!("je"),
-                Jump::JNE => print!("jne"),
-                Jump::JGE => print!("jge"),
-                Jump::JG => print!("jg"),
+            let name = match Jump::from(inst.ifun) {
+                Jump::JMP => "jmp",
+                Jump::JLE => "jle",
+                Jump::JL => "jl",
+                Jump::JE => "je",
+                Jump::JNE => "jne",
+                Jump::JGE => "jge",
+                Jump::JG => "jg",
+            };
+
+            code_operand(name, inst.val_c.unwrap(), symbols)
+        }
+        Icode::CALL => code_operand("call", inst.val_c.unwrap(), symbols),
+        Icode::RET => String::from("ret"),
+        Icode::IRET => String::from("iret"),
+        Icode::CAS => {
+            let mut s = format!("cas %{}, 0x{:x}", inst.ra, inst.val_c.unwrap());
+            if inst.rb != Register::NOREG {
+                s += &format!("(%{})", inst.rb);
             }
-
-            print!(" 0x{:x}", inst.val_c.unwrap());
+            s
         }
-        Icode::CALL => print!("call 0x{:x}", inst.val_c.unwrap()),
-        Icode::RET => print!("ret"),
-        Icode::PUSHQ => print!("pushq %{}", inst.ra.to_string()),
-        Icode::POPQ => print!("popq %{}", inst.ra.to_string()),
-        Icode::INVALID => (), // impossible
+        Icode::PUSHQ => format!("pushq %{}", inst.ra),
+        Icode::POPQ => format!("popq %{}", inst.ra),
+        Icode::SYSCALL => String::from(match Sys::from(inst.ifun) {
+            Sys::WRCH => "wrch",
+            Sys::WRINT => "wrint",
+            Sys::RDINT => "rdint",
+            Sys::EXIT => "exit",
+        }),
+        Icode::EXT(_) | Icode::INVALID => String::new(), // impossible
     }
 }
 
-pub fn disassemble_code(memory: &Box<[u8]>, phdr: &ElfPhdr, hdr: &ElfHdr) {
+/// Renders a code address operand as `label` or `label+0xN` when a symbol precedes it.
+fn code_operand(mnemonic: &str, addr: Address, symbols: &SymTab) -> String {
+    match symbols.lookup(addr) {
+        Some((name, 0)) => format!("{} {}  # 0x{:x}", mnemonic, name, addr),
+        Some((name, offset)) => format!("{} {}+0x{:x}  # 0x{:x}", mnemonic, name, offset, addr),
+        None => format!("{} 0x{:x}", mnemonic, addr),
+    }
+}
+
+pub fn disassemble_code(memory: &[u8], phdr: &ElfPhdr, hdr: &ElfHdr, symbols: &SymTab, isa: &Isa) {
     // fake cpu to hold pc
-    let mut cpu = Cpu {
-        reg: [0u64; NUM_REGS as usize],
-        zf: false,
-        of: false,
-        sf: false,
-        pc: phdr.vaddr as u64,
-        stat: Stat::AOK,
-    };
+    let mut cpu = Cpu::new(phdr.vaddr as u64);
+    cpu.isa = isa.clone();
 
     println!(
         "  0x{:03x}:                               | .pos 0x{:03x} code",
@@ -427,10 +613,16 @@ pub fn disassemble_code(memory: &Box<[u8]>, phdr: &ElfPhdr, hdr: &ElfHdr) {
 
     let end = phdr.vaddr + phdr.size;
     while cpu.pc < end as u64 {
-        if cpu.pc == hdr.entry as u64 {
+        // label the entry point and any known symbols
+        let label = match symbols.name_at(cpu.pc) {
+            Some(name) => Some(name),
+            None if cpu.pc == hdr.entry as u64 => Some("_start"),
+            None => None,
+        };
+        if let Some(label) = label {
             println!(
-                "  0x{:03x}:                               | _start:",
-                cpu.pc
+                "  0x{:03x}:                               | {}:",
+                cpu.pc, label
             );
         }
 
@@ -453,15 +645,14 @@ pub fn disassemble_code(memory: &Box<[u8]>, phdr: &ElfPhdr, hdr: &ElfHdr) {
         }
         print!("|   ");
 
-        disassemble(&inst);
-        println!();
+        println!("{}", disassemble(&inst, symbols));
         cpu.pc = inst.val_p;
     }
 
     println!();
 }
 
-pub fn disassemble_data(memory: &Box<[u8]>, phdr: &ElfPhdr) {
+pub fn disassemble_data(memory: &[u8], phdr: &ElfPhdr, symbols: &SymTab) {
     let mut addr = phdr.vaddr;
     let max_addr = addr + phdr.size;
 
@@ -471,6 +662,13 @@ pub fn disassemble_data(memory: &Box<[u8]>, phdr: &ElfPhdr) {
     );
 
     while addr < max_addr {
+        if let Some(label) = symbols.name_at(addr as u64) {
+            println!(
+                "  0x{:03x}:                               | {}:",
+                addr, label
+            );
+        }
+
         print!("  0x{:03x}: ", addr);
         for i in 0..8 {
             let byte = memory.get((addr + i) as usize).unwrap(); // memory must be valid, checked
@@ -488,7 +686,7 @@ pub fn disassemble_data(memory: &Box<[u8]>, phdr: &ElfPhdr) {
     println!();
 }
 
-pub fn disassemble_rodata(memory: &Box<[u8]>, phdr: &ElfPhdr) {
+pub fn disassemble_rodata(memory: &[u8], phdr: &ElfPhdr) {
     let mut addr = phdr.vaddr;
     let max_addr = phdr.vaddr + phdr.size;
     let mut s: String = String::from(""); // the string to print
@@ -564,14 +762,11 @@ fn inv_inst(inst: &mut Inst, cpu: &mut Cpu) {
     cpu.stat = Stat::INS;
 }
 
-fn memtoi(memory: &Box<[u8]>, start: Address) -> Option<Address> {
+pub fn memtoi(memory: &[u8], start: Address) -> Option<Address> {
     let mut n: Address = 0;
 
-    for i in (start..(start + 8)).rev() {
-        let byte = match memory.get(i as usize) {
-            Some(v) => v,
-            None => return None,
-        };
+    for i in (start..start.checked_add(8)?).rev() {
+        let byte = memory.get(i as usize)?;
 
         n <<= 8;
         n += *byte as Address;
@@ -0,0 +1,412 @@
+use std::io::BufRead;
+
+use crate::{
+    disas::{disassemble, fetch, Address, Cpu, Icode, Inst, Jump, Opq, Register, Stat, Sys},
+    hook::Hook,
+    interrupt::{deliver, raise, Vector},
+    memory::Memory,
+    sym::SymTab,
+};
+
+/// Runs the program until the cpu leaves the AOK state. Returns the number of instructions
+/// executed. In trace mode every instruction and the resulting cpu state is printed.
+pub fn execute(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    symbols: &SymTab,
+    trace: bool,
+) -> u64 {
+    let mut count = 0;
+
+    while cpu.stat == Stat::AOK {
+        let inst = match step(cpu, memory, hooks) {
+            Some(v) => v,
+            None => break,
+        };
+        count += 1;
+
+        if trace {
+            println!("\nExecuting: {}", disassemble(&inst, symbols));
+            dump_cpu(cpu);
+        }
+    }
+
+    count
+}
+
+/// Fetches and executes the instruction at pc, notifying the hooks and devices, then
+/// delivers any exception or interrupt to its handler. Returns `None` without executing
+/// anything if the instruction is invalid and no handler takes over; a fetch fault in the
+/// first instruction of a handler is not delivered again.
+pub fn step(cpu: &mut Cpu, memory: &mut Memory, hooks: &mut [Box<dyn Hook>]) -> Option<Inst> {
+    let mut inst = fetch(cpu, &memory.ram);
+    if inst.icode == Icode::INVALID {
+        let vector = Vector::from_stat(cpu.stat)?;
+        if !raise(cpu, memory, hooks, vector) {
+            return None;
+        }
+        inst = fetch(cpu, &memory.ram);
+        if inst.icode == Icode::INVALID {
+            return None;
+        }
+    }
+
+    let pc = cpu.pc;
+    exec_inst(cpu, memory, hooks, &inst);
+    for hook in hooks.iter_mut() {
+        hook.after(pc, &inst, cpu);
+    }
+    memory.tick();
+    deliver(cpu, memory, hooks);
+
+    Some(inst)
+}
+
+/// Executes a single fetched instruction with the instruction set that decoded it,
+/// updating registers, flags, memory and pc.
+pub fn exec_inst(cpu: &mut Cpu, memory: &mut Memory, hooks: &mut [Box<dyn Hook>], inst: &Inst) {
+    let set = match &inst.set {
+        Some(v) => v.clone(),
+        None => return, // invalid instructions are never executed
+    };
+    if let Some(next_pc) = set.execute(cpu, memory, hooks, inst) {
+        cpu.pc = next_pc;
+    }
+}
+
+/// Executes one of the built-in instructions. Returns the next pc, or `None` after a
+/// failed memory access.
+pub fn exec_builtin(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    inst: &Inst,
+) -> Option<Address> {
+    let val_a = get_reg(cpu, inst.ra);
+    let val_b = get_reg(cpu, inst.rb);
+    let mut next_pc = inst.val_p;
+
+    match inst.icode {
+        Icode::HALT => next_pc = cpu.pc,
+        Icode::NOP => (),
+        Icode::CMOV => {
+            if cond(cpu, inst.ifun) {
+                set_reg(cpu, inst.rb, val_a);
+            }
+        }
+        Icode::IRMOVQ => set_reg(cpu, inst.rb, inst.val_c.unwrap()),
+        Icode::RMMOVQ => {
+            let addr = inst.val_c.unwrap().wrapping_add(val_b);
+            if !store(cpu, memory, hooks, addr, val_a) {
+                cpu.stat = Stat::ADR;
+                return None;
+            }
+        }
+        Icode::MRMOVQ => {
+            let addr = inst.val_c.unwrap().wrapping_add(val_b);
+            match load(cpu, memory, hooks, addr) {
+                Some(v) => set_reg(cpu, inst.ra, v),
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            }
+        }
+        Icode::OPQ => {
+            let val_e = alu(cpu, inst.ifun, val_a, val_b);
+            if cpu.stat == Stat::DIV {
+                return None;
+            }
+            set_reg(cpu, inst.rb, val_e);
+        }
+        Icode::JUMP => {
+            let taken = cond(cpu, inst.ifun);
+            if taken {
+                next_pc = inst.val_c.unwrap();
+            }
+            if !matches!(Jump::from(inst.ifun), Jump::JMP) {
+                for hook in hooks.iter_mut() {
+                    hook.branch(cpu.pc, inst.val_c.unwrap(), taken);
+                }
+            }
+        }
+        Icode::CALL => {
+            let rsp = cpu.reg[Register::RSP as usize].wrapping_sub(8);
+            if !store(cpu, memory, hooks, rsp, inst.val_p) {
+                cpu.stat = Stat::ADR;
+                return None;
+            }
+            cpu.reg[Register::RSP as usize] = rsp;
+            next_pc = inst.val_c.unwrap();
+        }
+        Icode::RET => {
+            let rsp = cpu.reg[Register::RSP as usize];
+            match load(cpu, memory, hooks, rsp) {
+                Some(v) => next_pc = v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            }
+            cpu.reg[Register::RSP as usize] = rsp.wrapping_add(8);
+        }
+        Icode::PUSHQ => {
+            let rsp = cpu.reg[Register::RSP as usize].wrapping_sub(8);
+            if !store(cpu, memory, hooks, rsp, val_a) {
+                cpu.stat = Stat::ADR;
+                return None;
+            }
+            cpu.reg[Register::RSP as usize] = rsp;
+        }
+        Icode::POPQ => {
+            let rsp = cpu.reg[Register::RSP as usize];
+            let val_m = match load(cpu, memory, hooks, rsp) {
+                Some(v) => v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            };
+            cpu.reg[Register::RSP as usize] = rsp.wrapping_add(8);
+            set_reg(cpu, inst.ra, val_m);
+        }
+        Icode::IADDQ => {
+            let val_e = alu(cpu, Opq::ADD as u8, inst.val_c.unwrap(), val_b);
+            set_reg(cpu, inst.rb, val_e);
+        }
+        Icode::LEAVE => {
+            let rbp = cpu.reg[Register::RBP as usize];
+            match load(cpu, memory, hooks, rbp) {
+                Some(v) => cpu.reg[Register::RBP as usize] = v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            }
+            cpu.reg[Register::RSP as usize] = rbp.wrapping_add(8);
+        }
+        Icode::IRET => {
+            let rsp = cpu.reg[Register::RSP as usize];
+            let flags = match load(cpu, memory, hooks, rsp) {
+                Some(v) => v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            };
+            match load(cpu, memory, hooks, rsp.wrapping_add(8)) {
+                Some(v) => next_pc = v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            }
+            cpu.reg[Register::RSP as usize] = rsp.wrapping_add(16);
+            cpu.set_flags(flags);
+        }
+        Icode::CAS => {
+            // the comparison sets the flags like subq of the memory quad from %rax
+            let addr = inst.val_c.unwrap().wrapping_add(val_b);
+            let old = match load(cpu, memory, hooks, addr) {
+                Some(v) => v,
+                None => {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            };
+            let rax = cpu.reg[Register::RAX as usize];
+            alu(cpu, Opq::SUB as u8, old, rax);
+            if old == rax {
+                if !store(cpu, memory, hooks, addr, val_a) {
+                    cpu.stat = Stat::ADR;
+                    return None;
+                }
+            } else {
+                cpu.reg[Register::RAX as usize] = old;
+            }
+        }
+        Icode::SYSCALL => {
+            syscall(cpu, memory, inst.ifun);
+            if cpu.stat == Stat::HLT {
+                next_pc = cpu.pc;
+            }
+        }
+        Icode::EXT(_) | Icode::INVALID => return None, // impossible
+    }
+
+    Some(next_pc)
+}
+
+/// Performs a console system call. The argument is taken from %rdi and any result is
+/// returned in %rax.
+pub fn syscall(cpu: &mut Cpu, memory: &mut Memory, ifun: u8) {
+    let arg = cpu.reg[Register::RDI as usize];
+
+    match Sys::from(ifun) {
+        Sys::WRCH => memory.journal.output(&(arg as u8 as char).to_string()),
+        Sys::WRINT => memory.journal.output(&(arg as i64).to_string()),
+        Sys::RDINT => {
+            cpu.reg[Register::RAX as usize] = memory
+                .journal
+                .input("rdint", |console| read_int(console) as Address)
+        }
+        Sys::EXIT => cpu.stat = Stat::HLT,
+    }
+}
+
+/// Reads a decimal or 0x-prefixed hex integer from a line of the console. Returns 0 on
+/// end of input or if the line is not a number.
+fn read_int(console: &mut dyn BufRead) -> i64 {
+    let mut line = String::new();
+    if console.read_line(&mut line).is_err() {
+        return 0;
+    }
+
+    let line = line.trim();
+    let (neg, digits) = match line.strip_prefix('-') {
+        Some(v) => (true, v),
+        None => (false, line),
+    };
+    let n = match digits.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16),
+        None => digits.parse::<i64>(),
+    }
+    .unwrap_or(0);
+
+    if neg {
+        n.wrapping_neg()
+    } else {
+        n
+    }
+}
+
+pub fn dump_cpu(cpu: &Cpu) {
+    println!("Y86 CPU state:");
+    println!(
+        "    PC: {:016x}   flags: Z{} S{} O{}     {}",
+        cpu.pc, cpu.zf as u8, cpu.sf as u8, cpu.of as u8, cpu.stat
+    );
+
+    let regs = [
+        Register::RAX,
+        Register::RCX,
+        Register::RDX,
+        Register::RBX,
+        Register::RSP,
+        Register::RBP,
+        Register::RSI,
+        Register::RDI,
+        Register::R8,
+        Register::R9,
+        Register::R10,
+        Register::R11,
+        Register::R12,
+        Register::R13,
+        Register::R14,
+    ];
+    for (i, reg) in regs.iter().enumerate() {
+        print!(
+            "{:>6}: {:016x}",
+            format!("%{}", reg),
+            cpu.reg[*reg as usize]
+        );
+        if i % 2 == 1 || i == regs.len() - 1 {
+            println!();
+        } else {
+            print!("  ");
+        }
+    }
+}
+
+/// Loads a quad for the current instruction and notifies the hooks.
+pub fn load(
+    cpu: &Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    addr: Address,
+) -> Option<Address> {
+    let val = memory.load(addr)?;
+    for hook in hooks.iter_mut() {
+        hook.mem_read(cpu.pc, addr);
+    }
+
+    Some(val)
+}
+
+/// Stores a quad for the current instruction and notifies the hooks.
+pub fn store(
+    cpu: &Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    addr: Address,
+    val: Address,
+) -> bool {
+    if !memory.store(addr, val) {
+        return false;
+    }
+    for hook in hooks.iter_mut() {
+        hook.mem_write(cpu.pc, addr);
+    }
+
+    true
+}
+
+pub fn get_reg(cpu: &Cpu, reg: Register) -> Address {
+    match reg {
+        Register::NOREG => 0,
+        _ => cpu.reg[reg as usize],
+    }
+}
+
+pub fn set_reg(cpu: &mut Cpu, reg: Register, val: Address) {
+    if reg != Register::NOREG {
+        cpu.reg[reg as usize] = val;
+    }
+}
+
+/// Evaluates the condition for a jump or conditional move.
+pub fn cond(cpu: &Cpu, ifun: u8) -> bool {
+    let lt = cpu.sf ^ cpu.of;
+    match Jump::from(ifun) {
+        Jump::JMP => true,
+        Jump::JLE => lt || cpu.zf,
+        Jump::JL => lt,
+        Jump::JE => cpu.zf,
+        Jump::JNE => !cpu.zf,
+        Jump::JGE => !lt,
+        Jump::JG => !lt && !cpu.zf,
+    }
+}
+
+/// Computes `val_b OP val_a` and sets the condition codes. Division and modulo are
+/// signed and set OF only for the minimum value divided by -1; shifts use the low six
+/// bits of `val_a` and clear OF. A zero divisor sets the DIV status and leaves the
+/// condition codes unchanged.
+pub fn alu(cpu: &mut Cpu, ifun: u8, val_a: Address, val_b: Address) -> Address {
+    let (a, b) = (val_a as i64, val_b as i64);
+    let shift = (val_a & 0x3f) as u32;
+    let (res, of) = match Opq::from(ifun) {
+        Opq::DIV | Opq::MOD if a == 0 => {
+            cpu.stat = Stat::DIV;
+            return val_b;
+        }
+        Opq::ADD => b.overflowing_add(a),
+        Opq::SUB => b.overflowing_sub(a),
+        Opq::AND => (b & a, false),
+        Opq::XOR => (b ^ a, false),
+        Opq::MUL => b.overflowing_mul(a),
+        Opq::DIV => b.overflowing_div(a),
+        Opq::MOD => b.overflowing_rem(a),
+        Opq::SHL => (b << shift, false),
+        Opq::SAR => (b >> shift, false),
+        Opq::SHR => ((val_b >> shift) as i64, false),
+        Opq::OR => (b | a, false),
+    };
+
+    cpu.zf = res == 0;
+    cpu.sf = res < 0;
+    cpu.of = of;
+
+    res as Address
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::{BTreeSet, VecDeque},
+    io::{ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{
+    disas::{Address, Cpu, Stat, NUM_REGS},
+    exec::step,
+    hook::Hook,
+    memory::Memory,
+};
+
+/// Register numbers after the general purpose registers.
+const PC: usize = NUM_REGS as usize;
+const EFLAGS: usize = PC + 1;
+
+/// Instructions between checks for an interrupt from the debugger while continuing.
+const POLL_INTERVAL: u64 = 1024;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+<architecture>i386:x86-64</architecture>
+<feature name="org.y86sim.core">
+<reg name="rax" bitsize="64" type="int64" regnum="0"/>
+<reg name="rcx" bitsize="64" type="int64"/>
+<reg name="rdx" bitsize="64" type="int64"/>
+<reg name="rbx" bitsize="64" type="int64"/>
+<reg name="rsp" bitsize="64" type="data_ptr"/>
+<reg name="rbp" bitsize="64" type="data_ptr"/>
+<reg name="rsi" bitsize="64" type="int64"/>
+<reg name="rdi" bitsize="64" type="int64"/>
+<reg name="r8" bitsize="64" type="int64"/>
+<reg name="r9" bitsize="64" type="int64"/>
+<reg name="r10" bitsize="64" type="int64"/>
+<reg name="r11" bitsize="64" type="int64"/>
+<reg name="r12" bitsize="64" type="int64"/>
+<reg name="r13" bitsize="64" type="int64"/>
+<reg name="r14" bitsize="64" type="int64"/>
+<reg name="pc" bitsize="64" type="code_ptr"/>
+<reg name="eflags" bitsize="64" type="int64"/>
+</feature>
+</target>
+"#;
+
+/// A GDB remote serial protocol connection controlling one cpu. Registers are numbered
+/// as in `Cpu::reg`, followed by `pc` and `eflags` (ZF, SF and OF at their x86 bit
+/// positions), and are described to the debugger through `target.xml`.
+struct Session<'a> {
+    stream: TcpStream,
+    /// Bytes read while polling for an interrupt, kept for the packet reader
+    pending: VecDeque<u8>,
+    ack: bool,
+    breakpoints: BTreeSet<Address>,
+    cpu: &'a mut Cpu,
+    memory: &'a mut Memory,
+    hooks: &'a mut [Box<dyn Hook>],
+    count: u64,
+}
+
+/// Waits for a debugger to connect to `addr` and runs the program under its control until
+/// it detaches, kills the program, or the program stops. Returns the number of
+/// instructions executed.
+pub fn serve(
+    addr: &str,
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+) -> Result<u64> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Failed to listen on {}", addr))?;
+    println!("Waiting for gdb on {}", addr);
+    let (stream, peer) = listener.accept()?;
+    println!("gdb connected from {}", peer);
+
+    let mut session = Session {
+        stream,
+        pending: VecDeque::new(),
+        ack: true,
+        breakpoints: BTreeSet::new(),
+        cpu,
+        memory,
+        hooks,
+        count: 0,
+    };
+    session.run()?;
+
+    Ok(session.count)
+}
+
+impl Session<'_> {
+    fn run(&mut self) -> Result<()> {
+        loop {
+            let packet = match self.receive()? {
+                Some(v) => v,
+                None => return Ok(()), // connection closed
+            };
+
+            let reply = match packet.as_bytes().first() {
+                Some(b'?') => self.stop_reply(),
+                Some(b'g') => self.read_regs(),
+                Some(b'G') => self.write_regs(&packet[1..]),
+                Some(b'p') => self.read_reg(&packet[1..]),
+                Some(b'P') => self.write_reg(&packet[1..]),
+                Some(b'm') => self.read_mem(&packet[1..]),
+                Some(b'M') => self.write_mem(&packet[1..]),
+                Some(b's') => self.resume(&packet[1..], true)?,
+                Some(b'c') => self.resume(&packet[1..], false)?,
+                Some(b'Z') => self.breakpoint(&packet[1..], true),
+                Some(b'z') => self.breakpoint(&packet[1..], false),
+                Some(b'H') => "OK".to_string(),
+                Some(b'k') => return Ok(()),
+                Some(b'D') => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Some(b'q') | Some(b'Q') => self.query(&packet),
+                _ => String::new(), // unsupported
+            };
+            self.send(&reply)?;
+
+            if reply.starts_with('W') {
+                return Ok(()); // the program has finished
+            }
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return xfer(TARGET_XML, args).unwrap_or_else(|| "E01".to_string());
+        }
+
+        match packet {
+            "QStartNoAckMode" => {
+                self.ack = false;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        match self.cpu.stat {
+            Stat::AOK => "S05".to_string(), // SIGTRAP
+            Stat::HLT => "W00".to_string(),
+            Stat::ADR => "S0b".to_string(), // SIGSEGV
+            Stat::INS => "S04".to_string(), // SIGILL
+            Stat::DIV => "S08".to_string(), // SIGFPE
+        }
+    }
+
+    fn get_reg(&self, n: usize) -> Option<u64> {
+        let val = match n {
+            PC => self.cpu.pc,
+            EFLAGS => self.cpu.flags(),
+            _ => *self.cpu.reg.get(n)?,
+        };
+
+        Some(val)
+    }
+
+    fn set_reg(&mut self, n: usize, val: u64) -> bool {
+        match n {
+            PC => self.cpu.pc = val,
+            EFLAGS => self.cpu.set_flags(val),
+            _ => match self.cpu.reg.get_mut(n) {
+                Some(reg) => *reg = val,
+                None => return false,
+            },
+        }
+
+        true
+    }
+
+    fn read_regs(&self) -> String {
+        (0..=EFLAGS)
+            .map(|n| to_hex(&self.get_reg(n).unwrap_or(0).to_le_bytes()))
+            .collect()
+    }
+
+    fn write_regs(&mut self, args: &str) -> String {
+        let bytes = match from_hex(args) {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+        for (n, quad) in bytes.chunks_exact(8).enumerate() {
+            self.set_reg(n, u64::from_le_bytes(quad.try_into().unwrap()));
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_reg(&self, args: &str) -> String {
+        match usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|n| self.get_reg(n))
+        {
+            Some(val) => to_hex(&val.to_le_bytes()),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_reg(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(n, val)| {
+            let n = usize::from_str_radix(n, 16).ok()?;
+            let val: [u8; 8] = from_hex(val)?.try_into().ok()?;
+            Some((n, u64::from_le_bytes(val)))
+        });
+
+        match parsed {
+            Some((n, val)) if self.set_reg(n, val) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_mem(&self, args: &str) -> String {
+        match mem_range(args, self.memory.ram.len()) {
+            Some((start, len)) => to_hex(&self.memory.ram[start..start + len]),
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_mem(&mut self, args: &str) -> String {
+        let (range, data) = match args.split_once(':') {
+            Some(v) => v,
+            None => return "E01".to_string(),
+        };
+
+        match (mem_range(range, self.memory.ram.len()), from_hex(data)) {
+            (Some((start, len)), Some(bytes)) if bytes.len() == len => {
+                self.memory.ram[start..start + len].copy_from_slice(&bytes);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    /// Handles `Z0`/`z0` and `Z1`/`z1`; both kinds are kept in the simulator rather than
+    /// written into memory.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut fields = args.split(',');
+        let kind = fields.next();
+        let addr = fields.next().and_then(|a| u64::from_str_radix(a, 16).ok());
+
+        match (kind, addr) {
+            (Some("0") | Some("1"), Some(addr)) => {
+                if insert {
+                    self.breakpoints.insert(addr);
+                } else {
+                    self.breakpoints.remove(&addr);
+                }
+                "OK".to_string()
+            }
+            _ => String::new(),
+        }
+    }
+
+    /// Steps one instruction or continues until a breakpoint, an interrupt from the
+    /// debugger, or the program stops. An optional address resumes from there.
+    fn resume(&mut self, args: &str, single: bool) -> Result<String> {
+        if let Ok(addr) = u64::from_str_radix(args, 16) {
+            self.cpu.pc = addr;
+        }
+
+        while self.cpu.stat == Stat::AOK {
+            if step(self.cpu, self.memory, self.hooks).is_none() {
+                break;
+            }
+            self.count += 1;
+
+            if single || self.breakpoints.contains(&self.cpu.pc) {
+                break;
+            }
+            if self.count.is_multiple_of(POLL_INTERVAL) && self.interrupted()? {
+                return Ok("S02".to_string()); // SIGINT
+            }
+        }
+
+        Ok(self.stop_reply())
+    }
+
+    /// Checks, without blocking, whether the debugger has sent an interrupt. Other bytes
+    /// are kept for the next packet.
+    fn interrupted(&mut self) -> Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match read {
+            Ok(1) if byte[0] == 0x03 => Ok(true),
+            Ok(1) => {
+                self.pending.push_back(byte[0]);
+                Ok(false)
+            }
+            Ok(_) => bail!("gdb disconnected"),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>> {
+        if let Some(b) = self.pending.pop_front() {
+            return Ok(Some(b));
+        }
+        let mut byte = [0u8];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next `$data#checksum` packet, skipping acks and stray interrupts.
+    fn receive(&mut self) -> Result<Option<String>> {
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'$') => break,
+                Some(_) => (),
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match self.read_byte()? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(b) => data.push(b),
+            }
+        }
+        let mut sum = [0u8; 2];
+        for b in sum.iter_mut() {
+            *b = self.read_byte()?.context("gdb disconnected")?;
+        }
+
+        let ok = std::str::from_utf8(&sum)
+            .ok()
+            .and_then(|s| u8::from_str_radix(s, 16).ok())
+            .is_some_and(|s| s == checksum(&data));
+        if self.ack {
+            self.stream.write_all(if ok { b"+" } else { b"-" })?;
+        }
+        if !ok {
+            return self.receive();
+        }
+
+        Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+    }
+
+    fn send(&mut self, data: &str) -> Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        if self.ack {
+            // wait for the acknowledgement, resending on a nak
+            loop {
+                match self.read_byte()? {
+                    None | Some(b'+') => break,
+                    Some(b'-') => self.stream.write_all(packet.as_bytes())?,
+                    Some(_) => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, b| sum.wrapping_add(*b))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Parses `addr,len` and checks that the range lies within memory.
+fn mem_range(args: &str, size: usize) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = usize::from_str_radix(addr, 16).ok()?;
+    let len = usize::from_str_radix(len, 16).ok()?;
+    if addr.checked_add(len)? > size {
+        return None;
+    }
+
+    Some((addr, len))
+}
+
+/// Answers a `qXfer` read of `offset,length` from `doc`.
+fn xfer(doc: &str, args: &str) -> Option<String> {
+    let (offset, len) = args.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?.min(doc.len());
+    let len = usize::from_str_radix(len, 16).ok()?;
+    let end = offset.saturating_add(len).min(doc.len());
+
+    let prefix = if end == doc.len() { "l" } else { "m" };
+    Some(format!("{}{}", prefix, &doc[offset..end]))
+}
@@ -0,0 +1,686 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet},
+};
+
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    disas::{fetch, memtoi, Cpu, Icode, Inst, Jump, Register, Stat},
+    exec::{alu, cond, get_reg, load, set_reg, store},
+    hook::Hook,
+    memory::Memory,
+    parse_num,
+    seq::Signals,
+};
+
+/// Signals computed by the simulated hardware rather than the HCL file, in the order the
+/// stages produce them.
+const HARDWARE: [&str; 14] = [
+    "pc",
+    "imem_icode",
+    "imem_ifun",
+    "imem_error",
+    "rA",
+    "rB",
+    "valC",
+    "valP",
+    "valA",
+    "valB",
+    "valE",
+    "Cnd",
+    "valM",
+    "dmem_error",
+];
+
+/// Signals the HCL file must define.
+const CONTROL: [&str; 19] = [
+    "icode",
+    "ifun",
+    "instr_valid",
+    "need_regids",
+    "need_valC",
+    "srcA",
+    "srcB",
+    "dstE",
+    "dstM",
+    "aluA",
+    "aluB",
+    "alufun",
+    "set_cc",
+    "mem_read",
+    "mem_write",
+    "mem_addr",
+    "mem_data",
+    "Stat",
+    "new_pc",
+];
+
+/// Values of the C names that CS:APP HCL files bind constants to.
+const C_CONSTANTS: [(&str, u64); 35] = [
+    ("I_HALT", 0x0),
+    ("I_NOP", 0x1),
+    ("I_RRMOVQ", 0x2),
+    ("I_IRMOVQ", 0x3),
+    ("I_RMMOVQ", 0x4),
+    ("I_MRMOVQ", 0x5),
+    ("I_ALU", 0x6),
+    ("I_JMP", 0x7),
+    ("I_CALL", 0x8),
+    ("I_RET", 0x9),
+    ("I_PUSHQ", 0xa),
+    ("I_POPQ", 0xb),
+    ("I_IADDQ", 0xc),
+    ("I_LEAVE", 0xd),
+    ("F_NONE", 0x0),
+    ("REG_RSP", 0x4),
+    ("REG_RBP", 0x5),
+    ("REG_NONE", 0xf),
+    ("A_ADD", 0x0),
+    ("A_SUB", 0x1),
+    ("A_AND", 0x2),
+    ("A_XOR", 0x3),
+    ("A_MUL", 0x4),
+    ("A_DIV", 0x5),
+    ("A_MOD", 0x6),
+    ("A_SHL", 0x7),
+    ("A_SAR", 0x8),
+    ("A_SHR", 0x9),
+    ("A_OR", 0xa),
+    ("C_YES", 0x0),
+    ("STAT_AOK", 1),
+    ("STAT_HLT", 2),
+    ("STAT_ADR", 3),
+    ("STAT_INS", 4),
+    ("STAT_DIV", 5),
+];
+
+const SAOK: u64 = 1;
+const SHLT: u64 = 2;
+const SADR: u64 = 3;
+const SINS: u64 = 4;
+const SDIV: u64 = 5;
+
+enum Expr {
+    Num(u64),
+    Name(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(String, Box<Expr>, Box<Expr>),
+    In(Box<Expr>, Vec<Expr>),
+    Case(Vec<(Expr, Expr)>),
+}
+
+/// Control logic for the SEQ processor written in the CS:APP hardware control language.
+/// `boolsig`/`wordsig` declarations name either a constant, via the C name it is bound
+/// to (`I_HALT`, `REG_RSP`, `STAT_AOK`, ...), or a signal produced by the hardware (`pc`,
+/// `imem_icode`, `rA`, `valC`, `valE`, `Cnd`, `valM`, ...). `bool`/`word` definitions
+/// compute the control signals, such as `need_regids`, `srcA`, `alufun` and `new_pc`.
+pub struct Hcl {
+    consts: BTreeMap<String, u64>,
+    defs: BTreeMap<String, Expr>,
+}
+
+impl Hcl {
+    pub fn parse(text: &str) -> Result<Hcl> {
+        let tokens = tokenize(text)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let mut hcl = Hcl {
+            consts: BTreeMap::new(),
+            defs: BTreeMap::new(),
+        };
+        let mut signals: BTreeSet<String> = BTreeSet::new();
+
+        while let Some((line, token)) = parser.peek() {
+            let line = *line;
+            let token = token.clone();
+            parser.pos += 1;
+
+            match token.as_str() {
+                "quote" => {
+                    parser.quoted()?;
+                }
+                "boolsig" | "wordsig" | "intsig" => {
+                    let name = parser.name()?;
+                    let c = parser.quoted()?;
+                    match C_CONSTANTS.iter().find(|(n, _)| *n == c) {
+                        Some((_, val)) => {
+                            hcl.consts.insert(name, *val);
+                        }
+                        None => match parse_num(&c) {
+                            Ok(val) => {
+                                hcl.consts.insert(name, val);
+                            }
+                            Err(_) => {
+                                ensure!(
+                                    HARDWARE.contains(&name.as_str()),
+                                    "Line {}: unknown signal {} ('{}')",
+                                    line,
+                                    name,
+                                    c
+                                );
+                                signals.insert(name);
+                            }
+                        },
+                    }
+                }
+                "bool" | "word" | "int" => {
+                    let name = parser.name()?;
+                    parser.expect("=")?;
+                    let expr = parser.expr()?;
+                    parser.expect(";")?;
+                    ensure!(
+                        !hcl.defs.contains_key(&name),
+                        "Line {}: {} is defined twice",
+                        line,
+                        name
+                    );
+                    hcl.defs.insert(name, expr);
+                }
+                _ => bail!(
+                    "Line {}: expected a declaration but found '{}'",
+                    line,
+                    token
+                ),
+            }
+        }
+
+        for name in CONTROL {
+            ensure!(hcl.defs.contains_key(name), "HCL does not define {}", name);
+        }
+        for expr in hcl.defs.values() {
+            hcl.check(expr, &signals)?;
+        }
+
+        Ok(hcl)
+    }
+
+    /// Checks that every name in `expr` is a constant, a declared hardware signal or a
+    /// definition.
+    fn check(&self, expr: &Expr, signals: &BTreeSet<String>) -> Result<()> {
+        match expr {
+            Expr::Num(_) => (),
+            Expr::Name(name) => ensure!(
+                self.consts.contains_key(name)
+                    || signals.contains(name)
+                    || self.defs.contains_key(name),
+                "HCL uses undeclared name {}",
+                name
+            ),
+            Expr::Not(e) => self.check(e, signals)?,
+            Expr::And(a, b) | Expr::Or(a, b) | Expr::Compare(_, a, b) => {
+                self.check(a, signals)?;
+                self.check(b, signals)?;
+            }
+            Expr::In(e, set) => {
+                self.check(e, signals)?;
+                for e in set.iter() {
+                    self.check(e, signals)?;
+                }
+            }
+            Expr::Case(arms) => {
+                for (c, v) in arms.iter() {
+                    self.check(c, signals)?;
+                    self.check(v, signals)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Performs one SEQ cycle with the control signals computed from the HCL. Fetch reads
+    /// the register byte and constant word only when `need_regids` and `need_valC` ask
+    /// for them, and an instruction the HCL does not mark valid stops in fetch.
+    pub fn cycle(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+    ) -> Result<Signals> {
+        let pc = cpu.pc;
+        let env = Env {
+            hcl: self,
+            hw: RefCell::new(BTreeMap::new()),
+            cache: RefCell::new(BTreeMap::new()),
+            busy: RefCell::new(BTreeSet::new()),
+        };
+
+        // fetch
+        let byte = memory.ram.get(pc as usize).copied();
+        env.set("pc", pc);
+        env.set("imem_icode", byte.unwrap_or(0) as u64 >> 4);
+        env.set("imem_ifun", byte.unwrap_or(0) as u64 & 0xf);
+        env.set("imem_error", byte.is_none() as u64);
+
+        let mut s = Signals::new(pc, empty_inst());
+        s.icode = env.get("icode")? as u8;
+        s.ifun = env.get("ifun")? as u8;
+        s.instr_valid = env.get("instr_valid")? != 0;
+        s.need_regids = env.get("need_regids")? != 0;
+        s.need_val_c = env.get("need_valC")? != 0;
+        s.imem_error = byte.is_none();
+
+        let mut addr = pc + 1;
+        let (mut ra, mut rb) = (0xf, 0xf);
+        if s.need_regids {
+            match memory.ram.get(addr as usize) {
+                Some(b) => (ra, rb) = (b >> 4, b & 0xf),
+                None => s.imem_error = true,
+            }
+            addr += 1;
+        }
+        let mut val_c = 0;
+        if s.need_val_c {
+            match memtoi(&memory.ram, addr) {
+                Some(v) => val_c = v,
+                None => s.imem_error = true,
+            }
+            addr += 8;
+        }
+        s.inst = Inst {
+            icode: Icode::INVALID,
+            ifun: s.ifun,
+            ra: Register::from(ra),
+            rb: Register::from(rb),
+            val_c: s.need_val_c.then_some(val_c),
+            val_p: addr,
+            set: None,
+        };
+
+        if s.imem_error || !s.instr_valid {
+            s.stat = if s.imem_error { Stat::ADR } else { Stat::INS };
+            cpu.stat = s.stat;
+            return Ok(s);
+        }
+
+        // use the built-in decoding for the mnemonic and hooks when it agrees
+        let mut fake = Cpu::new(pc);
+        fake.isa = cpu.isa.clone();
+        let builtin = fetch(&mut fake, &memory.ram);
+        if builtin.icode != Icode::INVALID && builtin.val_p == addr {
+            s.inst = builtin;
+        }
+        // system calls reach the host only through the built-in SEQ logic
+        if s.inst.icode == Icode::SYSCALL {
+            bail!("System calls are not supported by the HCL model; use --seq instead");
+        }
+
+        env.set("rA", ra as u64);
+        env.set("rB", rb as u64);
+        env.set("valC", val_c);
+        env.set("valP", addr);
+
+        // decode
+        s.src_a = Register::from(env.get("srcA")? as u8);
+        s.src_b = Register::from(env.get("srcB")? as u8);
+        s.val_a = get_reg(cpu, s.src_a);
+        s.val_b = get_reg(cpu, s.src_b);
+        env.set("valA", s.val_a);
+        env.set("valB", s.val_b);
+
+        // execute, with Cnd taken from the condition codes before this instruction
+        s.cnd = cond(cpu, s.ifun);
+        s.alu_a = env.get("aluA")?;
+        s.alu_b = env.get("aluB")?;
+        s.alu_fun = env.get("alufun")? as u8;
+        s.set_cc = env.get("set_cc")? != 0;
+        let flags = (cpu.zf, cpu.sf, cpu.of);
+        s.val_e = alu(cpu, s.alu_fun, s.alu_a, s.alu_b);
+        let div_error = cpu.stat == Stat::DIV;
+        cpu.stat = Stat::AOK;
+        if !s.set_cc {
+            (cpu.zf, cpu.sf, cpu.of) = flags;
+        }
+        env.set("valE", s.val_e);
+        env.set("Cnd", s.cnd as u64);
+        if s.icode == 7 && !matches!(Jump::from(s.ifun), Jump::JMP) {
+            for hook in hooks.iter_mut() {
+                hook.branch(pc, val_c, s.cnd);
+            }
+        }
+
+        // memory
+        s.mem_read = env.get("mem_read")? != 0;
+        s.mem_write = env.get("mem_write")? != 0;
+        if s.mem_read || s.mem_write {
+            s.mem_addr = env.get("mem_addr")?;
+        }
+        if s.mem_write {
+            s.mem_data = env.get("mem_data")?;
+        }
+        if s.mem_read {
+            match load(cpu, memory, hooks, s.mem_addr) {
+                Some(v) => s.val_m = v,
+                None => s.dmem_error = true,
+            }
+        }
+        if s.mem_write && !store(cpu, memory, hooks, s.mem_addr, s.mem_data) {
+            s.dmem_error = true;
+        }
+        env.set("valM", s.val_m);
+        env.set("dmem_error", s.dmem_error as u64);
+
+        s.stat = match env.get("Stat")? {
+            SAOK => Stat::AOK,
+            SHLT => Stat::HLT,
+            SADR => Stat::ADR,
+            SINS => Stat::INS,
+            SDIV => Stat::DIV,
+            v => bail!("Stat has no status with code {}", v),
+        };
+        if div_error && s.stat == Stat::AOK {
+            s.stat = Stat::DIV;
+        }
+        cpu.stat = s.stat;
+
+        // write-back, with valM taking priority when both ports name the same register
+        s.dst_e = Register::from(env.get("dstE")? as u8);
+        s.dst_m = Register::from(env.get("dstM")? as u8);
+        if !s.dmem_error && s.stat != Stat::DIV {
+            set_reg(cpu, s.dst_e, s.val_e);
+            set_reg(cpu, s.dst_m, s.val_m);
+        }
+
+        // PC update; like the executor, a processor that stops keeps its pc
+        s.new_pc = env.get("new_pc")?;
+        if s.stat == Stat::AOK {
+            cpu.pc = s.new_pc;
+        }
+
+        for hook in hooks.iter_mut() {
+            hook.after(pc, &s.inst, cpu);
+        }
+        memory.tick();
+
+        Ok(s)
+    }
+}
+
+fn empty_inst() -> Inst {
+    Inst {
+        icode: Icode::INVALID,
+        ifun: 0,
+        ra: Register::NOREG,
+        rb: Register::NOREG,
+        val_c: None,
+        val_p: 0,
+        set: None,
+    }
+}
+
+/// The signal values of one cycle. Definitions are evaluated when first needed and then
+/// remembered, so a definition may only use hardware signals its stage has produced.
+struct Env<'a> {
+    hcl: &'a Hcl,
+    hw: RefCell<BTreeMap<&'static str, u64>>,
+    cache: RefCell<BTreeMap<String, u64>>,
+    busy: RefCell<BTreeSet<String>>,
+}
+
+impl Env<'_> {
+    fn set(&self, name: &'static str, val: u64) {
+        self.hw.borrow_mut().insert(name, val);
+    }
+
+    fn get(&self, name: &str) -> Result<u64> {
+        if let Some(val) = self.hcl.consts.get(name) {
+            return Ok(*val);
+        }
+        if let Some(val) = self.hw.borrow().get(name) {
+            return Ok(*val);
+        }
+        if let Some(val) = self.cache.borrow().get(name) {
+            return Ok(*val);
+        }
+
+        let expr = match self.hcl.defs.get(name) {
+            Some(v) => v,
+            None => bail!("Signal {} is used before its stage has computed it", name),
+        };
+        ensure!(
+            self.busy.borrow_mut().insert(name.to_string()),
+            "Signal {} depends on itself",
+            name
+        );
+        let val = self
+            .eval(expr)
+            .with_context(|| format!("Failed to evaluate {}", name))?;
+        self.busy.borrow_mut().remove(name);
+        self.cache.borrow_mut().insert(name.to_string(), val);
+
+        Ok(val)
+    }
+
+    fn eval(&self, expr: &Expr) -> Result<u64> {
+        let val = match expr {
+            Expr::Num(n) => *n,
+            Expr::Name(name) => self.get(name)?,
+            Expr::Not(e) => (self.eval(e)? == 0) as u64,
+            Expr::And(a, b) => (self.eval(a)? != 0 && self.eval(b)? != 0) as u64,
+            Expr::Or(a, b) => (self.eval(a)? != 0 || self.eval(b)? != 0) as u64,
+            Expr::Compare(op, a, b) => {
+                let (a, b) = (self.eval(a)? as i64, self.eval(b)? as i64);
+                let res = match op.as_str() {
+                    "==" => a == b,
+                    "!=" => a != b,
+                    "<" => a < b,
+                    "<=" => a <= b,
+                    ">" => a > b,
+                    _ => a >= b,
+                };
+                res as u64
+            }
+            Expr::In(e, set) => {
+                let val = self.eval(e)?;
+                let mut found = false;
+                for e in set.iter() {
+                    if self.eval(e)? == val {
+                        found = true;
+                        break;
+                    }
+                }
+                found as u64
+            }
+            Expr::Case(arms) => {
+                for (c, v) in arms.iter() {
+                    if self.eval(c)? != 0 {
+                        return self.eval(v);
+                    }
+                }
+                0
+            }
+        };
+
+        Ok(val)
+    }
+}
+
+/// Splits HCL into tokens tagged with their line numbers. Quoted strings keep their
+/// quotes so they can be told apart from names.
+fn tokenize(text: &str) -> Result<Vec<(usize, String)>> {
+    let mut tokens = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            if c == '#' {
+                break;
+            } else if c.is_whitespace() {
+                i += 1;
+            } else if c == '\'' {
+                let start = i;
+                i += 1;
+                while i < chars.len() && chars[i] != '\'' {
+                    i += 1;
+                }
+                ensure!(i < chars.len(), "Line {}: unterminated quote", n + 1);
+                i += 1;
+                tokens.push((n + 1, chars[start..i].iter().collect()));
+            } else if c.is_alphanumeric() || c == '_' {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push((n + 1, chars[start..i].iter().collect()));
+            } else {
+                let pair: String = chars[i..chars.len().min(i + 2)].iter().collect();
+                let len = match pair.as_str() {
+                    "==" | "!=" | "<=" | ">=" | "&&" | "||" => 2,
+                    _ if "=;:,[]{}()<>!-".contains(c) => 1,
+                    _ => bail!("Line {}: unexpected '{}'", n + 1, c),
+                };
+                tokens.push((n + 1, chars[i..i + len].iter().collect()));
+                i += len;
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, String)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&(usize, String)> {
+        self.tokens.get(self.pos)
+    }
+
+    fn peek_is(&self, token: &str) -> bool {
+        self.peek().is_some_and(|(_, t)| t == token)
+    }
+
+    fn next(&mut self) -> Result<(usize, String)> {
+        let token = self.peek().context("Unexpected end of HCL")?.clone();
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn expect(&mut self, token: &str) -> Result<()> {
+        let (line, next) = self.next()?;
+        ensure!(
+            next == token,
+            "Line {}: expected '{}' but found '{}'",
+            line,
+            token,
+            next
+        );
+        Ok(())
+    }
+
+    fn name(&mut self) -> Result<String> {
+        let (line, name) = self.next()?;
+        ensure!(
+            name.starts_with(|c: char| c.is_alphabetic() || c == '_'),
+            "Line {}: expected a name but found '{}'",
+            line,
+            name
+        );
+        Ok(name)
+    }
+
+    fn quoted(&mut self) -> Result<String> {
+        let (line, token) = self.next()?;
+        match token.strip_prefix('\'').and_then(|t| t.strip_suffix('\'')) {
+            Some(v) => Ok(v.trim().to_string()),
+            None => bail!(
+                "Line {}: expected a quoted string but found '{}'",
+                line,
+                token
+            ),
+        }
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        let mut lhs = self.and()?;
+        while self.peek_is("||") {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut lhs = self.not()?;
+        while self.peek_is("&&") {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.not()?));
+        }
+        Ok(lhs)
+    }
+
+    fn not(&mut self) -> Result<Expr> {
+        if self.peek_is("!") {
+            self.pos += 1;
+            return Ok(Expr::Not(Box::new(self.not()?)));
+        }
+        self.compare()
+    }
+
+    fn compare(&mut self) -> Result<Expr> {
+        let lhs = self.primary()?;
+
+        if self.peek_is("in") {
+            self.pos += 1;
+            self.expect("{")?;
+            let mut set = vec![self.expr()?];
+            while self.peek_is(",") {
+                self.pos += 1;
+                set.push(self.expr()?);
+            }
+            self.expect("}")?;
+            return Ok(Expr::In(Box::new(lhs), set));
+        }
+
+        for op in ["==", "!=", "<", "<=", ">", ">="] {
+            if self.peek_is(op) {
+                self.pos += 1;
+                let rhs = self.primary()?;
+                return Ok(Expr::Compare(op.to_string(), Box::new(lhs), Box::new(rhs)));
+            }
+        }
+
+        Ok(lhs)
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let (line, token) = self.next()?;
+        match token.as_str() {
+            "(" => {
+                let expr = self.expr()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            "-" => match self.primary()? {
+                Expr::Num(n) => Ok(Expr::Num(n.wrapping_neg())),
+                _ => bail!("Line {}: only numbers can be negated", line),
+            },
+            "[" => {
+                let mut arms = Vec::new();
+                while !self.peek_is("]") {
+                    let c = self.expr()?;
+                    self.expect(":")?;
+                    let v = self.expr()?;
+                    self.expect(";")?;
+                    arms.push((c, v));
+                }
+                self.pos += 1;
+                Ok(Expr::Case(arms))
+            }
+            _ if token.starts_with(|c: char| c.is_ascii_digit()) => match parse_num(&token) {
+                Ok(n) => Ok(Expr::Num(n)),
+                Err(_) => bail!("Line {}: bad number '{}'", line, token),
+            },
+            _ if token.starts_with(|c: char| c.is_alphabetic() || c == '_') => {
+                Ok(Expr::Name(token))
+            }
+            _ => bail!("Line {}: unexpected '{}'", line, token),
+        }
+    }
+}
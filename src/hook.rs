@@ -0,0 +1,19 @@
+use crate::disas::{Address, Cpu, Inst};
+
+/// Observes a running program. The executor notifies every registered hook and asks each
+/// one for a report when execution stops.
+pub trait Hook {
+    /// A quad was loaded from `addr` by the instruction at `pc`.
+    fn mem_read(&mut self, _pc: Address, _addr: Address) {}
+
+    /// A quad was stored to `addr` by the instruction at `pc`.
+    fn mem_write(&mut self, _pc: Address, _addr: Address) {}
+
+    /// The conditional jump at `pc` to `target` was resolved.
+    fn branch(&mut self, _pc: Address, _target: Address, _taken: bool) {}
+
+    /// The instruction at `pc` finished executing, leaving the cpu in the given state.
+    fn after(&mut self, _pc: Address, _inst: &Inst, _cpu: &Cpu) {}
+
+    fn report(&self) {}
+}
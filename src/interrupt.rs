@@ -0,0 +1,84 @@
+use crate::{
+    disas::{Address, Cpu, Register, Stat},
+    exec::store,
+    hook::Hook,
+    memory::Memory,
+};
+
+/// Exceptions and interrupts, in the order of their entries in the vector table.
+#[derive(PartialEq, Clone, Copy)]
+pub enum Vector {
+    ADR,
+    INS,
+    DIV,
+    TIMER,
+}
+
+impl Vector {
+    /// The exception a faulting status is delivered as.
+    pub fn from_stat(stat: Stat) -> Option<Vector> {
+        match stat {
+            Stat::ADR => Some(Vector::ADR),
+            Stat::INS => Some(Vector::INS),
+            Stat::DIV => Some(Vector::DIV),
+            Stat::AOK | Stat::HLT => None,
+        }
+    }
+}
+
+/// Address of the handler for `vector`, read from the vector table. An entry of zero
+/// means there is no handler.
+pub fn handler(cpu: &Cpu, memory: &mut Memory, vector: Vector) -> Option<Address> {
+    let table = cpu.vectors?;
+    match memory.load(table.wrapping_add(8 * vector as Address))? {
+        0 => None,
+        addr => Some(addr),
+    }
+}
+
+/// Transfers control to the handler for `vector`. The pc to resume at and then the flags
+/// are pushed, interrupts are disabled and the cpu continues in the handler, which returns
+/// with `iret`. For a fault the saved pc is that of the faulting instruction. Returns
+/// false, leaving the registers alone, if there is no handler or the frame cannot be
+/// pushed.
+pub fn raise(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    vector: Vector,
+) -> bool {
+    let addr = match handler(cpu, memory, vector) {
+        Some(v) => v,
+        None => return false,
+    };
+
+    let rsp = cpu.reg[Register::RSP as usize];
+    let frame = rsp.wrapping_sub(16);
+    if !store(cpu, memory, hooks, frame.wrapping_add(8), cpu.pc)
+        || !store(cpu, memory, hooks, frame, cpu.flags())
+    {
+        return false;
+    }
+
+    cpu.reg[Register::RSP as usize] = frame;
+    cpu.pc = addr;
+    cpu.ie = false;
+    cpu.stat = Stat::AOK;
+
+    true
+}
+
+/// Delivers the exception for a faulting status or, while interrupts are enabled, a
+/// device interrupt. Called after every instruction. Returns true if a handler was
+/// entered.
+pub fn deliver(cpu: &mut Cpu, memory: &mut Memory, hooks: &mut [Box<dyn Hook>]) -> bool {
+    if let Some(vector) = Vector::from_stat(cpu.stat) {
+        return raise(cpu, memory, hooks, vector);
+    }
+
+    // only acknowledge an interrupt that can be taken
+    if cpu.stat != Stat::AOK || !cpu.ie || handler(cpu, memory, Vector::TIMER).is_none() {
+        return false;
+    }
+    memory.interrupt() && raise(cpu, memory, hooks, Vector::TIMER)
+}
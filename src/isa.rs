@@ -0,0 +1,214 @@
+use std::rc::Rc;
+
+use crate::{
+    disas::{render_builtin, Address, Cpu, Ext, Icode, Inst},
+    exec::exec_builtin,
+    hook::Hook,
+    memory::Memory,
+    sym::SymTab,
+};
+
+/// What a register field of an instruction may hold.
+#[derive(PartialEq, Clone, Copy)]
+pub enum Field {
+    /// A register id from 0 to E
+    Reg,
+    /// Only F, meaning no register
+    NoReg,
+    /// A register id or F
+    Any,
+}
+
+/// The layout of an instruction: the icode and ifun byte, then an optional register
+/// byte, then an optional 8-byte constant.
+#[derive(Clone, Copy)]
+pub struct Encoding {
+    /// What the rA and rB fields may hold, if there is a register byte
+    pub regids: Option<(Field, Field)>,
+    /// Whether the instruction has a constant word
+    pub val_c: bool,
+}
+
+impl Encoding {
+    /// Length of the instruction in bytes.
+    pub fn length(&self) -> Address {
+        1 + self.regids.is_some() as Address + 8 * self.val_c as Address
+    }
+
+    /// Offset of the constant word from the start of the instruction.
+    pub fn val_c_at(&self) -> Address {
+        1 + self.regids.is_some() as Address
+    }
+}
+
+/// A set of instructions that can be registered with an [`Isa`]. The base Y86-64
+/// instructions and each `--ext` extension are sets; library users can add their own.
+pub trait Extension {
+    /// Short name of the set.
+    fn name(&self) -> &str;
+
+    /// The encoding of the instruction with `icode` and `ifun`, or `None` if the set does
+    /// not claim it.
+    fn encoding(&self, icode: u8, ifun: u8) -> Option<Encoding>;
+
+    /// The kind of instruction the analyses and the SEQ model see. Sets defined outside
+    /// this crate keep the default, which those treat as opaque.
+    fn icode(&self, icode: u8) -> Icode {
+        Icode::EXT(icode)
+    }
+
+    /// Renders a decoded instruction in assembly syntax.
+    fn render(&self, inst: &Inst, symbols: &SymTab) -> String;
+
+    /// Executes a decoded instruction, reporting memory accesses to the hooks through
+    /// [`crate::exec::load`] and [`crate::exec::store`]. Returns the address of the next
+    /// instruction, or `None` if the instruction faulted, in which case it sets
+    /// `cpu.stat` and pc is left at the faulting instruction.
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+        inst: &Inst,
+    ) -> Option<Address>;
+}
+
+/// The registry of instruction sets a cpu decodes. A set registered later takes
+/// precedence for the icodes it claims.
+#[derive(Clone)]
+pub struct Isa {
+    sets: Vec<Rc<dyn Extension>>,
+}
+
+impl Isa {
+    /// The base Y86-64 instruction set together with the given built-in extensions.
+    pub fn new(ext: &[Ext]) -> Isa {
+        let mut isa = Isa {
+            sets: vec![Rc::new(Base)],
+        };
+        for e in ext {
+            isa.register(Rc::new(*e));
+        }
+
+        isa
+    }
+
+    /// The instruction set a program runs with: the given extensions, and `iret` as well
+    /// when the program has a vector table, since its handlers return with it.
+    pub fn for_program(ext: &[Ext], vectors: Option<Address>) -> Isa {
+        let mut isa = Isa::new(ext);
+        if vectors.is_some() && !ext.contains(&Ext::IRET) {
+            isa.register(Rc::new(Ext::IRET));
+        }
+
+        isa
+    }
+
+    /// Adds a set of instructions to the ones the cpu decodes.
+    pub fn register(&mut self, set: Rc<dyn Extension>) {
+        self.sets.push(set);
+    }
+
+    /// Finds the set claiming `icode` and `ifun` and the encoding it gives.
+    pub fn lookup(&self, icode: u8, ifun: u8) -> Option<(Rc<dyn Extension>, Encoding)> {
+        self.sets
+            .iter()
+            .rev()
+            .find_map(|set| set.encoding(icode, ifun).map(|enc| (set.clone(), enc)))
+    }
+}
+
+/// The twelve instructions of the base Y86-64 instruction set.
+pub struct Base;
+
+impl Extension for Base {
+    fn name(&self) -> &str {
+        "y86-64"
+    }
+
+    fn encoding(&self, icode: u8, ifun: u8) -> Option<Encoding> {
+        let (ifuns, regids, val_c) = match Icode::from(icode) {
+            Icode::HALT | Icode::NOP | Icode::RET => (1, None, false),
+            Icode::CMOV => (7, Some((Field::Reg, Field::Reg)), false),
+            Icode::IRMOVQ => (1, Some((Field::NoReg, Field::Reg)), true),
+            Icode::RMMOVQ | Icode::MRMOVQ => (1, Some((Field::Reg, Field::Any)), true),
+            Icode::OPQ => (4, Some((Field::Reg, Field::Reg)), false),
+            Icode::JUMP => (7, None, true),
+            Icode::CALL => (1, None, true),
+            Icode::PUSHQ | Icode::POPQ => (1, Some((Field::Reg, Field::NoReg)), false),
+            _ => return None,
+        };
+
+        (ifun < ifuns).then_some(Encoding { regids, val_c })
+    }
+
+    fn icode(&self, icode: u8) -> Icode {
+        Icode::from(icode)
+    }
+
+    fn render(&self, inst: &Inst, symbols: &SymTab) -> String {
+        render_builtin(inst, symbols)
+    }
+
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+        inst: &Inst,
+    ) -> Option<Address> {
+        exec_builtin(cpu, memory, hooks, inst)
+    }
+}
+
+impl Extension for Ext {
+    fn name(&self) -> &str {
+        match self {
+            Ext::SYSCALL => "syscall",
+            Ext::IADDQ => "iaddq",
+            Ext::LEAVE => "leave",
+            Ext::ALU => "alu",
+            Ext::IRET => "iret",
+            Ext::CAS => "cas",
+        }
+    }
+
+    fn encoding(&self, icode: u8, ifun: u8) -> Option<Encoding> {
+        // iret and cas share icode F
+        let (ifuns, regids, val_c) = match (self, Icode::from(icode)) {
+            (Ext::SYSCALL, Icode::SYSCALL) => (0..4, None, false),
+            (Ext::IADDQ, Icode::IADDQ) => (0..1, Some((Field::NoReg, Field::Reg)), true),
+            (Ext::LEAVE, Icode::LEAVE) => (0..1, None, false),
+            (Ext::ALU, Icode::OPQ) => (0..11, Some((Field::Reg, Field::Reg)), false),
+            (Ext::IRET, Icode::INVALID) if icode == 0xf => (0..1, None, false),
+            (Ext::CAS, Icode::INVALID) if icode == 0xf => {
+                (1..2, Some((Field::Reg, Field::Any)), true)
+            }
+            _ => return None,
+        };
+
+        ifuns.contains(&ifun).then_some(Encoding { regids, val_c })
+    }
+
+    fn icode(&self, icode: u8) -> Icode {
+        match self {
+            Ext::IRET => Icode::IRET,
+            Ext::CAS => Icode::CAS,
+            _ => Icode::from(icode),
+        }
+    }
+
+    fn render(&self, inst: &Inst, symbols: &SymTab) -> String {
+        render_builtin(inst, symbols)
+    }
+
+    fn execute(
+        &self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+        inst: &Inst,
+    ) -> Option<Address> {
+        exec_builtin(cpu, memory, hooks, inst)
+    }
+}
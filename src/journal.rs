@@ -0,0 +1,251 @@
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, Cursor, Write as _},
+};
+
+use anyhow::{bail, Context, Result};
+
+use crate::{disas::Address, parse_num};
+
+#[derive(PartialEq, Clone, Copy)]
+enum Mode {
+    LIVE,
+    RECORD,
+    REPLAY,
+}
+
+/// One input consumed by the program: a read from an input device, named after the
+/// device, an `rdint` system call, or an interrupt, named `irq`.
+struct Event {
+    /// Instructions completed before the input was consumed
+    tick: u64,
+    kind: String,
+    val: Address,
+}
+
+impl Event {
+    fn describe(&self) -> String {
+        match self.kind.as_str() {
+            "irq" => format!("irq at instruction {}", self.tick),
+            _ => format!(
+                "{} of 0x{:x} at instruction {}",
+                self.kind, self.val, self.tick
+            ),
+        }
+    }
+}
+
+/// The program's console and the other inputs that make a run nondeterministic.
+/// Recording writes every input value and the timing of every interrupt to a log at halt;
+/// replaying feeds them back from the log instead of asking the console or the devices,
+/// so the run can be repeated exactly. Output goes to stdout unless it is captured.
+///
+/// The log has one input per line: the instruction count, the kind and, except for
+/// interrupts, the value.
+pub struct Journal {
+    mode: Mode,
+    path: String,
+    ticks: u64,
+    events: VecDeque<Event>,
+    diverged: bool,
+    captured: Option<String>,
+    fed: Option<Cursor<Vec<u8>>>,
+}
+
+impl Default for Journal {
+    fn default() -> Self {
+        Journal {
+            mode: Mode::LIVE,
+            path: String::new(),
+            ticks: 0,
+            events: VecDeque::new(),
+            diverged: false,
+            captured: None,
+            fed: None,
+        }
+    }
+}
+
+impl Journal {
+    /// Records the inputs of the run to `path`.
+    pub fn record(path: &str) -> Journal {
+        Journal {
+            mode: Mode::RECORD,
+            path: path.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Replays the inputs recorded in the log at `path`.
+    pub fn replay(path: &str) -> Result<Journal> {
+        let text = fs::read_to_string(path).context(format!("Failed to read {}", path))?;
+
+        let mut events = VecDeque::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let event = match fields[..] {
+                [tick, "irq"] => Event {
+                    tick: tick.parse()?,
+                    kind: String::from("irq"),
+                    val: 0,
+                },
+                [tick, kind, val] => Event {
+                    tick: tick.parse()?,
+                    kind: kind.to_string(),
+                    val: parse_num(val)?,
+                },
+                _ => bail!("{}:{}: Expected TICK KIND [VALUE]", path, i + 1),
+            };
+            events.push_back(event);
+        }
+
+        Ok(Journal {
+            mode: Mode::REPLAY,
+            path: path.to_string(),
+            events,
+            ..Default::default()
+        })
+    }
+
+    /// Takes console input from `text` instead of stdin.
+    pub fn feed(&mut self, text: &str) {
+        self.fed = Some(Cursor::new(text.as_bytes().to_vec()));
+    }
+
+    /// Collects the output of the program instead of printing it.
+    pub fn capture(&mut self) {
+        self.captured = Some(String::new());
+    }
+
+    /// The output collected since [`Journal::capture`].
+    pub fn captured(&self) -> &str {
+        self.captured.as_deref().unwrap_or("")
+    }
+
+    /// Writes output of the program to the console.
+    pub fn output(&mut self, text: &str) {
+        match &mut self.captured {
+            Some(out) => out.push_str(text),
+            None => {
+                print!("{}", text);
+                io::stdout().flush().ok();
+            }
+        }
+    }
+
+    /// Called once for every instruction executed.
+    pub fn tick(&mut self) {
+        self.ticks += 1;
+    }
+
+    /// Consumes an input of the given kind. Unless it is replayed, `live` produces it,
+    /// reading the console from the reader it is given.
+    pub fn input(&mut self, kind: &str, live: impl FnOnce(&mut dyn BufRead) -> Address) -> Address {
+        if self.mode == Mode::REPLAY {
+            match self.events.front() {
+                Some(e) if e.kind == kind && e.tick == self.ticks => {
+                    return self.events.pop_front().unwrap().val;
+                }
+                _ => self.diverge(&format!("{} at instruction {}", kind, self.ticks)),
+            }
+        }
+
+        let val = match &mut self.fed {
+            Some(text) => live(text),
+            None => live(&mut io::stdin().lock()),
+        };
+        if self.mode == Mode::RECORD {
+            self.events.push_back(Event {
+                tick: self.ticks,
+                kind: kind.to_string(),
+                val,
+            });
+        }
+
+        val
+    }
+
+    /// Polls for an interrupt. While replaying, an interrupt is raised exactly when one
+    /// was recorded and `live` is not asked.
+    pub fn interrupt(&mut self, live: impl FnOnce() -> bool) -> bool {
+        if self.mode == Mode::REPLAY {
+            return match self.events.front() {
+                Some(e) if e.kind == "irq" && e.tick == self.ticks => {
+                    self.events.pop_front();
+                    true
+                }
+                Some(e) if e.tick < self.ticks => {
+                    self.diverge(&format!("no irq at instruction {}", self.ticks));
+                    live()
+                }
+                _ => false,
+            };
+        }
+
+        let raised = live();
+        if raised && self.mode == Mode::RECORD {
+            self.events.push_back(Event {
+                tick: self.ticks,
+                kind: String::from("irq"),
+                val: 0,
+            });
+        }
+
+        raised
+    }
+
+    /// Writes the log when recording. When replaying, fails if the run consumed different
+    /// inputs than were recorded.
+    pub fn finish(&mut self) -> Result<()> {
+        match self.mode {
+            Mode::LIVE => (),
+            Mode::RECORD => {
+                let mut text = String::from("# y86sim input log\n");
+                for e in self.events.iter() {
+                    match e.kind.as_str() {
+                        "irq" => writeln!(text, "{} irq", e.tick)?,
+                        _ => writeln!(text, "{} {} 0x{:x}", e.tick, e.kind, e.val)?,
+                    }
+                }
+                fs::write(&self.path, text).context(format!("Failed to write {}", self.path))?;
+            }
+            Mode::REPLAY => {
+                if self.diverged {
+                    bail!("Replay of {} diverged", self.path);
+                }
+                if let Some(e) = self.events.front() {
+                    bail!(
+                        "Replay of {} stopped before {} recorded input(s), the first {}",
+                        self.path,
+                        self.events.len(),
+                        e.describe()
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports where the run first consumed an input that was not recorded, then falls
+    /// back to live input for the rest of the run.
+    fn diverge(&mut self, got: &str) {
+        let expected = match self.events.front() {
+            Some(e) => e.describe(),
+            None => String::from("the end of the log"),
+        };
+        println!(
+            "Replay of {} diverged: expected {}, got {}",
+            self.path, expected, got
+        );
+        self.diverged = true;
+        self.events.clear();
+        self.mode = Mode::LIVE;
+    }
+}
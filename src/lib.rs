@@ -0,0 +1,43 @@
+#![allow(clippy::upper_case_acronyms)]
+
+use anyhow::Result;
+
+pub mod batch;
+pub mod cache;
+pub mod call;
+pub mod callconv;
+pub mod check;
+pub mod debug;
+pub mod device;
+pub mod disas;
+pub mod error;
+pub mod exec;
+pub mod gdb;
+pub mod hcl;
+pub mod hook;
+pub mod interrupt;
+pub mod isa;
+pub mod journal;
+pub mod link;
+pub mod load;
+pub mod memcheck;
+pub mod memory;
+pub mod predict;
+pub mod seq;
+pub mod smp;
+pub mod stack;
+pub mod sym;
+pub mod toml;
+pub mod tui;
+pub mod yo;
+
+pub const MEMSIZE: u16 = 1 << 12;
+
+/// Parses a decimal or 0x-prefixed hex number from the command line.
+pub fn parse_num(s: &str) -> Result<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None => Ok(s.parse()?),
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::HashMap,
+    io::{Cursor, Read, Seek, SeekFrom, Write},
+    mem::size_of,
+};
+
+use anyhow::{bail, ensure, Context, Result};
+use binrw::{BinRead, BinWrite};
+
+use crate::{
+    check::{self, read_header, ElfHdr},
+    load::{self, read_phdr, ElfPhdr},
+    sym::{read_symbols, ElfSym, UNDEF},
+    MEMSIZE,
+};
+
+/// Entry of a relocation segment (program header type 3). The quad at virtual address
+/// `offset` is replaced by the address of symbol number `symbol` plus the value already
+/// stored there. This is how `call`, `jXX` and `irmovq` immediates and `.quad` data refer
+/// to labels in other segments or objects.
+#[repr(C)]
+#[derive(BinRead, BinWrite)]
+pub struct ElfRel {
+    pub offset: u32,
+    pub symbol: u32,
+}
+
+/// A relocatable Mini-ELF object.
+pub struct Object {
+    pub name: String,
+    pub entry: u16,
+    pub phdrs: Vec<ElfPhdr>,
+    pub segments: Vec<Vec<u8>>,
+    pub symbols: Vec<(String, ElfSym)>,
+    pub relocs: Vec<ElfRel>,
+}
+
+pub fn read_object(name: &str, bytes: Vec<u8>) -> Result<Object> {
+    let mut reader = Cursor::new(bytes);
+    let hdr = read_header(&mut reader)?;
+
+    let mut phdrs: Vec<ElfPhdr> = Vec::with_capacity(hdr.num_phdr as usize);
+    for i in 0..hdr.num_phdr {
+        let offset = (size_of::<ElfPhdr>() as u16)
+            .checked_mul(i)
+            .and_then(|v| v.checked_add(hdr.phdr_start))
+            .with_context(|| format!("{}: program header {} is out of range", name, i))?;
+        phdrs.push(read_phdr(&mut reader, offset)?);
+    }
+
+    let len = reader.get_ref().len() as u64;
+    let mut segments = Vec::with_capacity(phdrs.len());
+    let mut relocs = Vec::new();
+    for (j, phdr) in phdrs.iter().enumerate() {
+        ensure!(
+            phdr.vaddr.checked_add(phdr.size).is_some(),
+            "{}: segment {} extends past the end of the address space",
+            name,
+            j
+        );
+        ensure!(
+            phdr.offset as u64 + phdr.size as u64 <= len,
+            "{}: segment {} extends past the end of the file",
+            name,
+            j
+        );
+        reader.seek(SeekFrom::Start(phdr.offset as u64))?;
+        let mut buf = vec![0u8; phdr.size as usize];
+        reader.read_exact(&mut buf)?;
+
+        if phdr.ptype == 3 {
+            let mut rel_reader = Cursor::new(&buf);
+            for _ in 0..phdr.size as usize / size_of::<ElfRel>() {
+                relocs.push(ElfRel::read_le(&mut rel_reader)?);
+            }
+        }
+        segments.push(buf);
+    }
+
+    let symbols = read_symbols(&mut reader, &hdr)?;
+    for rel in relocs.iter() {
+        ensure!(
+            (rel.symbol as usize) < symbols.len(),
+            "{}: relocation at 0x{:x} refers to a missing symbol",
+            name,
+            rel.offset
+        );
+    }
+
+    Ok(Object {
+        name: name.to_string(),
+        entry: hdr.entry,
+        phdrs,
+        segments,
+        symbols,
+        relocs,
+    })
+}
+
+/// An output segment being built from the input segments of one kind.
+struct Section {
+    ptype: u16,
+    flags: u16,
+    vaddr: u32,
+    bytes: Vec<u8>,
+}
+
+/// Section index standing for the stack, which is not built from segment bytes.
+const STACK: usize = 3;
+
+/// The output section a segment is placed in: code, read-only data, data or the stack.
+/// DATA segments go by their W flag. RELOC segments are consumed by the linker.
+fn section_of(obj: &Object, j: usize) -> Result<Option<usize>> {
+    let phdr = &obj.phdrs[j];
+    Ok(match phdr.ptype {
+        0 if phdr.flags & 2 == 0 => Some(1),
+        0 => Some(2),
+        1 => Some(0),
+        2 => Some(STACK),
+        3 => None,
+        _ => bail!(
+            "{}: segment {} has unknown type {}",
+            obj.name,
+            j,
+            phdr.ptype
+        ),
+    })
+}
+
+/// Links objects into an executable Mini-ELF image. Code is laid out from `code_base`,
+/// followed by read-only data and data, and the stack occupies the top `stack_size` bytes
+/// of memory. STACK segments of the objects are placed at the top of the stack, so a
+/// label at their end marks the top of memory. Global symbols are resolved across
+/// objects and every relocation is applied.
+pub fn link(objects: &[Object], code_base: u32, stack_size: u32) -> Result<Vec<u8>> {
+    // lay out code, then read-only data, then data, each aligned to 8 bytes
+    let mut sections = vec![
+        Section {
+            ptype: 1,
+            flags: 5,
+            vaddr: code_base,
+            bytes: Vec::new(),
+        },
+        Section {
+            ptype: 0,
+            flags: 4,
+            vaddr: 0,
+            bytes: Vec::new(),
+        },
+        Section {
+            ptype: 0,
+            flags: 6,
+            vaddr: 0,
+            bytes: Vec::new(),
+        },
+    ];
+    let stack_base = (MEMSIZE as u32).checked_sub(stack_size).with_context(|| {
+        format!(
+            "A stack of 0x{:x} bytes does not fit in 0x{:x} bytes of memory",
+            stack_size, MEMSIZE
+        )
+    })?;
+    ensure!(
+        code_base <= stack_base,
+        "Code at 0x{:x} overlaps the stack at 0x{:x}",
+        code_base,
+        stack_base
+    );
+
+    let mut targets = Vec::with_capacity(objects.len());
+    for obj in objects.iter() {
+        targets.push(
+            (0..obj.phdrs.len())
+                .map(|j| section_of(obj, j))
+                .collect::<Result<Vec<_>>>()?,
+        );
+    }
+
+    let mut placed: Vec<Vec<Option<(usize, u32)>>> = Vec::new(); // (section, vaddr) per phdr
+    for obj in objects.iter() {
+        placed.push(vec![None; obj.phdrs.len()]);
+    }
+    let mut addr = code_base;
+    for (s, section) in sections.iter_mut().enumerate() {
+        addr = (addr + 7) & !7;
+        section.vaddr = addr;
+
+        for (i, obj) in objects.iter().enumerate() {
+            for j in 0..obj.phdrs.len() {
+                if targets[i][j] != Some(s) {
+                    continue;
+                }
+                while section.bytes.len() % 8 != 0 {
+                    section.bytes.push(0);
+                }
+                let at = section.vaddr as usize + section.bytes.len();
+                ensure!(
+                    at + obj.segments[j].len() <= stack_base as usize,
+                    "{}: segment {} at 0x{:x} overlaps the stack at 0x{:x}",
+                    obj.name,
+                    j,
+                    at,
+                    stack_base
+                );
+                placed[i][j] = Some((s, at as u32));
+                section.bytes.extend_from_slice(&obj.segments[j]);
+            }
+        }
+        let end = section.vaddr as usize + section.bytes.len();
+        ensure!(
+            end <= stack_base as usize,
+            "Program ends at 0x{:x}, overlapping the stack at 0x{:x}",
+            end,
+            stack_base
+        );
+        addr = end as u32;
+    }
+    for (i, obj) in objects.iter().enumerate() {
+        for (j, phdr) in obj.phdrs.iter().enumerate() {
+            if targets[i][j] != Some(STACK) {
+                continue;
+            }
+            ensure!(
+                phdr.size <= stack_size,
+                "{}: STACK segment {} of 0x{:x} bytes does not fit in a stack of 0x{:x} bytes",
+                obj.name,
+                j,
+                phdr.size,
+                stack_size
+            );
+            placed[i][j] = Some((STACK, MEMSIZE as u32 - phdr.size));
+        }
+    }
+
+    // relocated address of every symbol defined by an object
+    let mut defined: Vec<Vec<Option<u32>>> = Vec::new();
+    let mut globals: HashMap<&str, u32> = HashMap::new();
+    for (i, obj) in objects.iter().enumerate() {
+        let mut addrs = Vec::with_capacity(obj.symbols.len());
+        for (name, sym) in obj.symbols.iter() {
+            if sym.section == UNDEF {
+                addrs.push(None);
+                continue;
+            }
+            let addr = relocate(obj, &placed[i], sym.section as usize, sym.value)
+                .with_context(|| format!("{}: bad symbol {}", obj.name, name))?;
+            if sym.bind == 1 && globals.insert(name, addr).is_some() {
+                bail!("{}: multiple definition of `{}`", obj.name, name);
+            }
+            addrs.push(Some(addr));
+        }
+        defined.push(addrs);
+    }
+
+    // apply relocations
+    for (i, obj) in objects.iter().enumerate() {
+        for rel in obj.relocs.iter() {
+            let (name, _) = &obj.symbols[rel.symbol as usize];
+            let target = match defined[i][rel.symbol as usize] {
+                Some(v) => v,
+                None => *globals
+                    .get(name.as_str())
+                    .with_context(|| format!("{}: undefined reference to `{}`", obj.name, name))?,
+            };
+
+            let j = obj
+                .phdrs
+                .iter()
+                .position(|p| {
+                    p.ptype != 3
+                        && rel.offset >= p.vaddr
+                        && rel
+                            .offset
+                            .checked_add(8)
+                            .zip(p.vaddr.checked_add(p.size))
+                            .is_some_and(|(end, limit)| end <= limit)
+                })
+                .with_context(|| {
+                    format!(
+                        "{}: relocation at 0x{:x} is outside any segment",
+                        obj.name, rel.offset
+                    )
+                })?;
+            let (s, base) = placed[i][j].context("Relocation in an unlinked segment")?;
+            ensure!(
+                s != STACK,
+                "{}: relocation at 0x{:x} is in the STACK segment",
+                obj.name,
+                rel.offset
+            );
+            let at =
+                (base - sections[s].vaddr) as usize + (rel.offset - obj.phdrs[j].vaddr) as usize;
+
+            let field = sections[s].bytes.get_mut(at..at + 8).with_context(|| {
+                format!(
+                    "{}: relocation at 0x{:x} is outside its segment",
+                    obj.name, rel.offset
+                )
+            })?;
+            let addend = u64::from_le_bytes(field.try_into()?);
+            field.copy_from_slice(&(target as u64).wrapping_add(addend).to_le_bytes());
+        }
+    }
+
+    // entry point is _start if defined, otherwise the entry of the first object
+    let entry = match globals.get("_start") {
+        Some(v) => *v,
+        None => {
+            let obj = objects.first().context("No objects to link")?;
+            let j = obj
+                .phdrs
+                .iter()
+                .position(|p| {
+                    p.ptype == 1
+                        && (obj.entry as u32) >= p.vaddr
+                        && p.vaddr
+                            .checked_add(p.size)
+                            .is_some_and(|end| (obj.entry as u32) < end)
+                })
+                .context("Entry point is not in a code segment")?;
+            relocate(obj, &placed[0], j, obj.entry as u32)?
+        }
+    };
+
+    write_exec(
+        objects, &placed, &sections, &defined, entry, stack_base, stack_size,
+    )
+}
+
+/// Maps an address in segment `j` of an object to its linked address.
+fn relocate(obj: &Object, placed: &[Option<(usize, u32)>], j: usize, addr: u32) -> Result<u32> {
+    let phdr = obj.phdrs.get(j).context("Bad section index")?;
+    let (_, base) = placed[j].context("Symbol in a RELOC segment")?;
+    ensure!(
+        addr >= phdr.vaddr
+            && phdr
+                .vaddr
+                .checked_add(phdr.size)
+                .is_some_and(|end| addr <= end),
+        "Address 0x{:x} is outside its segment",
+        addr
+    );
+
+    (addr - phdr.vaddr)
+        .checked_add(base)
+        .context("Address is outside the address space")
+}
+
+fn write_exec(
+    objects: &[Object],
+    placed: &[Vec<Option<(usize, u32)>>],
+    sections: &[Section],
+    defined: &[Vec<Option<u32>>],
+    entry: u32,
+    stack_base: u32,
+    stack_size: u32,
+) -> Result<Vec<u8>> {
+    // empty sections get no program header; the stack comes last
+    let mut out_index = [0u16; STACK + 1];
+    let mut kept: Vec<&Section> = Vec::new();
+    for (s, section) in sections.iter().enumerate() {
+        if !section.bytes.is_empty() {
+            out_index[s] = kept.len() as u16;
+            kept.push(section);
+        }
+    }
+    out_index[STACK] = kept.len() as u16;
+    let sections = kept;
+    let num_phdr = sections.len() + 1;
+    let phdr_start = size_of::<ElfHdr>();
+    let mut offset = phdr_start + num_phdr * size_of::<ElfPhdr>();
+
+    let mut phdrs = Vec::with_capacity(num_phdr);
+    for section in sections.iter() {
+        phdrs.push(ElfPhdr {
+            offset: offset as u32,
+            size: section.bytes.len() as u32,
+            vaddr: section.vaddr,
+            ptype: section.ptype,
+            flags: section.flags,
+            magic: load::MAGIC,
+        });
+        offset += section.bytes.len();
+    }
+
+    // the loader reads every segment from the file, so the stack is stored as zeros
+    phdrs.push(ElfPhdr {
+        offset: offset as u32,
+        size: stack_size,
+        vaddr: stack_base,
+        ptype: 2,
+        flags: 6,
+        magic: load::MAGIC,
+    });
+    offset += stack_size as usize;
+
+    // every defined symbol is kept, with its section index in the output
+    let mut syms = Vec::new();
+    let mut strtab: Vec<u8> = Vec::new();
+    for (i, obj) in objects.iter().enumerate() {
+        for (k, (name, sym)) in obj.symbols.iter().enumerate() {
+            let addr = match defined[i][k] {
+                Some(v) => v,
+                None => continue,
+            };
+            let (s, _) = placed[i][sym.section as usize].unwrap();
+            syms.push(ElfSym {
+                name: strtab.len() as u32,
+                value: addr,
+                section: out_index[s],
+                bind: sym.bind,
+            });
+            strtab.extend_from_slice(name.as_bytes());
+            strtab.push(0);
+        }
+    }
+    let symtab = offset;
+    let strtab_start = symtab + syms.len() * size_of::<ElfSym>();
+    ensure!(
+        strtab_start + strtab.len() <= u16::MAX as usize,
+        "Executable is too large for Mini-ELF"
+    );
+
+    let hdr = ElfHdr {
+        version: 1,
+        entry: entry as u16,
+        phdr_start: phdr_start as u16,
+        num_phdr: num_phdr as u16,
+        symtab: if syms.is_empty() { 0 } else { symtab as u16 },
+        strtab: if syms.is_empty() {
+            0
+        } else {
+            strtab_start as u16
+        },
+        magic: check::MAGIC,
+    };
+
+    let mut out = Cursor::new(Vec::new());
+    hdr.write_le(&mut out)?;
+    for phdr in phdrs.iter() {
+        phdr.write_le(&mut out)?;
+    }
+    for section in sections.iter() {
+        out.get_mut().extend_from_slice(&section.bytes);
+    }
+    out.get_mut().resize(symtab, 0);
+    out.set_position(symtab as u64);
+    if !syms.is_empty() {
+        for sym in syms.iter() {
+            sym.write_le(&mut out)?;
+        }
+        out.write_all(&strtab)?;
+    }
+
+    Ok(out.into_inner())
+}
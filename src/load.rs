@@ -1,14 +1,23 @@
-use std::io::{Cursor, Read};
+use std::{
+    io::{Cursor, Read},
+    mem::size_of,
+};
 
 use anyhow::{ensure, Context, Result};
-use binrw::BinRead;
+use binrw::{BinRead, BinWrite};
 
-use crate::error::mem_access;
+use crate::{
+    check::{read_header, ElfHdr},
+    error::mem_access,
+    memory::Memory,
+    sym::{read_symbols, SymTab, UNDEF},
+    yo::read_yo,
+};
 
-const MAGIC: u32 = 0xdeadbeef;
+pub const MAGIC: u32 = 0xdeadbeef;
 
 #[repr(C)]
-#[derive(BinRead)]
+#[derive(BinRead, BinWrite)]
 pub struct ElfPhdr {
     pub offset: u32,
     pub size: u32,
@@ -28,11 +37,7 @@ pub fn read_phdr(reader: &mut Cursor<Vec<u8>>, offset: u16) -> Result<ElfPhdr> {
     Ok(phdr)
 }
 
-pub fn load_segment(
-    reader: &mut Cursor<Vec<u8>>,
-    memory: &mut Box<[u8]>,
-    phdr: &ElfPhdr,
-) -> Result<()> {
+pub fn load_segment(reader: &mut Cursor<Vec<u8>>, memory: &mut [u8], phdr: &ElfPhdr) -> Result<()> {
     reader.set_position(phdr.offset as u64);
     ensure!(reader.position() == phdr.offset as u64);
 
@@ -48,7 +53,49 @@ pub fn load_segment(
     Ok(())
 }
 
-pub fn dump_phdrs(phdrs: &Vec<ElfPhdr>) {
+/// Reads a Mini-ELF file and loads its segments into memory. Relocation segments are not
+/// loaded.
+pub fn load_elf(bytes: Vec<u8>, memory: &mut [u8]) -> Result<(ElfHdr, Vec<ElfPhdr>, SymTab)> {
+    let mut reader = Cursor::new(bytes);
+
+    // load the header
+    let hdr = read_header(&mut reader)?;
+
+    // load the program headers
+    let mut phdrs: Vec<ElfPhdr> = Vec::with_capacity(hdr.num_phdr as usize);
+    for i in 0..hdr.num_phdr {
+        let offset: u16 = hdr.phdr_start + size_of::<ElfPhdr>() as u16 * i;
+        phdrs.push(read_phdr(&mut reader, offset)?);
+    }
+
+    for phdr in phdrs.iter() {
+        if phdr.ptype != 3 {
+            load_segment(&mut reader, memory, phdr)?;
+        }
+    }
+
+    let mut symbols = SymTab::default();
+    for (name, sym) in read_symbols(&mut reader, &hdr)? {
+        if sym.section != UNDEF {
+            symbols.insert(sym.value as u64, &name);
+        }
+    }
+
+    Ok((hdr, phdrs, symbols))
+}
+
+/// Reads a Mini-ELF file, or a CS:APP listing if the name ends in `.yo`, and loads it
+/// into memory.
+pub fn load_file(path: &str, memory: &mut [u8]) -> Result<(ElfHdr, Vec<ElfPhdr>, SymTab)> {
+    let bytes = std::fs::read(path).context(format!("Failed to read {}", path))?;
+    if path.ends_with(".yo") {
+        read_yo(&String::from_utf8(bytes)?, memory)
+    } else {
+        load_elf(bytes, memory)
+    }
+}
+
+pub fn dump_phdrs(phdrs: &[ElfPhdr]) {
     println!(" Segment   Offset    Size      VirtAddr  Type      Flags");
 
     for (i, phdr) in phdrs.iter().enumerate() {
@@ -62,6 +109,7 @@ pub fn dump_phdrs(phdrs: &Vec<ElfPhdr>) {
         match phdr.ptype {
             0 => print!("DATA "),
             1 => print!("CODE "),
+            3 => print!("RELOC"),
             _ => print!("STACK"),
         }
 
@@ -86,7 +134,7 @@ pub fn dump_phdrs(phdrs: &Vec<ElfPhdr>) {
     }
 }
 
-pub fn dump_memory(memory: &Box<[u8]>, start: u16, end: u16) -> Result<()> {
+pub fn dump_memory(memory: &Memory, start: u16, end: u16) -> Result<()> {
     print!("Contents of memory from {:04x} to {:04x}:", start, end);
 
     // floor address for unaligned memory
@@ -94,7 +142,10 @@ pub fn dump_memory(memory: &Box<[u8]>, start: u16, end: u16) -> Result<()> {
 
     let mut i = 0;
     while addr + i < end {
-        let byte = memory.get(i as usize).context(mem_access(i as usize))?;
+        let byte = memory
+            .ram
+            .get((addr + i) as usize)
+            .context(mem_access((addr + i) as usize))?;
 
         if i % 16 == 0 {
             print!("\n  {:04x}  ", addr + i);
@@ -107,6 +158,8 @@ pub fn dump_memory(memory: &Box<[u8]>, start: u16, end: u16) -> Result<()> {
         // print spaces before start address
         if addr + i < start {
             print!("  ");
+        } else if memory.device_at((addr + i) as u64).is_some() {
+            print!("--");
         } else {
             print!("{:02x}", byte);
         }
@@ -115,5 +168,12 @@ pub fn dump_memory(memory: &Box<[u8]>, start: u16, end: u16) -> Result<()> {
     }
     println!();
 
+    // label device regions, whose contents are not shown
+    for (dev_start, dev_end, name) in memory.regions() {
+        if dev_start < end as u64 && dev_end > start as u64 {
+            println!("  {:04x}-{:04x}  [{}]", dev_start, dev_end - 1, name);
+        }
+    }
+
     Ok(())
 }
@@ -1,19 +1,37 @@
-use clap::{Parser, CommandFactory};
-use check::{dump_header, read_header};
-use load::{load_segment, read_phdr, ElfPhdr, dump_phdrs, dump_memory};
-use std::{io::Cursor, mem::size_of, process::exit};
-use crate::disas::{disassemble_code, disassemble_data, disassemble_rodata};
-
-mod check;
-mod load;
-mod disas;
-mod error;
-
-pub const MEMSIZE: u16 = 1 << 12;
+use clap::{CommandFactory, Parser, Subcommand};
+use std::io::{self, IsTerminal};
+use std::process::exit;
+use y86sim::batch::{junit, report, run_spec};
+use y86sim::cache::Cache;
+use y86sim::call::{run_call, setup, SENTINEL};
+use y86sim::callconv::CallConv;
+use y86sim::check::dump_header;
+use y86sim::debug::Debugger;
+use y86sim::device::parse_device;
+use y86sim::disas::{disassemble_code, disassemble_data, disassemble_rodata, Cpu, Ext, Register};
+use y86sim::exec::{dump_cpu, execute};
+use y86sim::gdb::serve;
+use y86sim::hcl::Hcl;
+use y86sim::hook::Hook;
+use y86sim::isa::Isa;
+use y86sim::journal::Journal;
+use y86sim::load::{dump_memory, dump_phdrs, load_file};
+use y86sim::memcheck::Memcheck;
+use y86sim::memory::Memory;
+use y86sim::predict::{Predictor, Scheme};
+use y86sim::seq::run_seq;
+use y86sim::smp::{run_threads, start_symbols, Schedule, Thread};
+use y86sim::stack::{analyze_stack, StackChecker};
+use y86sim::sym::SymTab;
+use y86sim::tui::Tui;
+use y86sim::{parse_num, MEMSIZE};
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Show the Mini-ELF header
     #[arg(short = 'H')]
     hdr: bool,
@@ -46,6 +64,22 @@ struct Args {
     #[arg(short = 'D')]
     disas_data: bool,
 
+    /// Analyze the call graph and worst-case stack usage
+    #[arg(long = "stack-usage")]
+    stack_usage: bool,
+
+    /// Check %rsp bounds and call/ret discipline during execution
+    #[arg(long = "check-stack")]
+    check_stack: bool,
+
+    /// Report uses of uninitialized memory and registers during execution
+    #[arg(long = "memcheck")]
+    memcheck: bool,
+
+    /// Check callee-saved and caller-saved register conventions during execution
+    #[arg(long = "check-abi")]
+    check_abi: bool,
+
     /// Execute program
     #[arg(short = 'e')]
     exec: bool,
@@ -54,8 +88,225 @@ struct Args {
     #[arg(short = 'E')]
     exec_trace: bool,
 
-    /// Mini-ELF object file
-    file: String,
+    /// Enable an instruction set extension
+    #[arg(long = "ext", visible_alias = "isa-ext", value_enum)]
+    ext: Vec<Ext>,
+
+    /// Attach a device: uart, timer[=PERIOD] or fb=FILE[:WxH], optionally followed by @ADDR
+    #[arg(long = "device", value_name = "SPEC")]
+    device: Vec<String>,
+
+    /// Vector table for exceptions and interrupts (defaults to the `vectors` symbol)
+    #[arg(long = "vectors", value_name = "ADDR")]
+    vectors: Option<String>,
+
+    /// Set a register before execution, e.g. rdi=10
+    #[arg(long = "set-reg", value_name = "REG=VAL")]
+    set_reg: Vec<String>,
+
+    /// Store a quad in memory after loading, e.g. 0x200=0x1234
+    #[arg(long = "set-mem", value_name = "ADDR=VAL")]
+    set_mem: Vec<String>,
+
+    /// Start execution at an address or symbol instead of the entry point
+    #[arg(long = "set-pc", value_name = "ADDR")]
+    set_pc: Option<String>,
+
+    /// Record console and device input and interrupt timing to a log file
+    #[arg(long = "record", value_name = "FILE", conflicts_with = "replay")]
+    record: Option<String>,
+
+    /// Replay the input and interrupts recorded in a log file
+    #[arg(long = "replay", value_name = "FILE")]
+    replay: Option<String>,
+
+    /// Start another thread sharing memory at entry=ADDR[,rsp=ADDR]
+    #[arg(long = "thread", value_name = "SPEC")]
+    thread: Vec<String>,
+
+    /// How the instructions of multiple threads are interleaved
+    #[arg(
+        long = "schedule",
+        value_enum,
+        value_name = "POLICY",
+        default_value = "rr"
+    )]
+    schedule: Schedule,
+
+    /// Seed for the random schedule
+    #[arg(long = "seed", value_name = "N", default_value_t = 0)]
+    seed: u64,
+
+    /// Simulate a data cache, e.g. sets=16,ways=2,block=16,replace=lru|fifo|random,write=back|through
+    #[arg(long = "cache", value_name = "SPEC")]
+    cache: Option<String>,
+
+    /// Simulate a branch predictor for conditional jumps (repeat to compare)
+    #[arg(long = "predictor", value_enum, value_name = "SCHEME")]
+    predictor: Vec<Scheme>,
+
+    /// Pause execution before the instruction at an address or symbol
+    #[arg(long = "break-at", value_name = "ADDR")]
+    break_at: Vec<String>,
+
+    /// Pause execution when a condition becomes true, e.g. 'rax==5 && [rsp]!=0'
+    #[arg(long = "break-if", value_name = "COND")]
+    break_if: Vec<String>,
+
+    /// Log every change to memory at ADDR[:LEN] (LEN defaults to a quad)
+    #[arg(long = "watch", value_name = "ADDR")]
+    watch: Vec<String>,
+
+    /// Execute under a GDB remote debugger listening on ADDR, e.g. 127.0.0.1:1234
+    #[arg(long = "gdb", value_name = "ADDR")]
+    gdb: Option<String>,
+
+    /// Execute on the SEQ stage model, tracing every signal in each cycle
+    #[arg(long = "seq")]
+    seq: bool,
+
+    /// Execute on the SEQ stage model with control logic from an HCL file
+    #[arg(long = "hcl", value_name = "FILE")]
+    hcl: Option<String>,
+
+    /// Execute in a full-screen terminal interface
+    #[arg(long = "tui")]
+    tui: bool,
+
+    /// Mini-ELF object file or CS:APP .yo listing
+    #[arg(required = true)]
+    file: Option<String>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the test cases of a TOML spec and summarize the results
+    Test {
+        /// Test spec listing the cases
+        spec: String,
+
+        /// Also write the results as JUnit XML
+        #[arg(long = "junit", value_name = "FILE")]
+        junit: Option<String>,
+    },
+
+    /// Call a function with arguments and print the value it returns in %rax
+    Call {
+        /// Mini-ELF object file or CS:APP .yo listing
+        file: String,
+
+        /// Function to call, by symbol or address
+        function: String,
+
+        /// Arguments passed in %rdi, %rsi, %rdx, %rcx, %r8 and %r9
+        #[arg(allow_negative_numbers = true)]
+        args: Vec<String>,
+
+        /// Enable an instruction set extension
+        #[arg(long = "ext", value_enum)]
+        ext: Vec<Ext>,
+
+        /// Trace every instruction
+        #[arg(short = 'E')]
+        trace: bool,
+    },
+}
+
+fn run_tests(spec: &str, xml: Option<&String>) -> ! {
+    let outcomes = match run_spec(spec) {
+        Ok(v) => v,
+        Err(e) => {
+            println!("{:#}", e);
+            exit(1);
+        }
+    };
+    let passed = report(&outcomes);
+    if let Some(path) = xml {
+        if std::fs::write(path, junit(spec, &outcomes)).is_err() {
+            println!("Failed to write {}", path);
+            exit(1);
+        }
+    }
+
+    exit(if passed { 0 } else { 1 });
+}
+
+fn call_function(file: &str, function: &str, args: &[String], ext: &[Ext], trace: bool) -> ! {
+    let mut memory = Memory::new(MEMSIZE as usize);
+    let (_, phdrs, symbols) = match load_file(file, &mut memory.ram) {
+        Ok(v) => v,
+        Err(e) => load_failed(file, e),
+    };
+
+    let func = match resolve(function, &symbols) {
+        Some(v) => v,
+        None => {
+            println!("Unknown function: {}", function);
+            exit(1);
+        }
+    };
+    let mut vals = Vec::with_capacity(args.len());
+    for arg in args.iter() {
+        // negative arguments are two's complement
+        let val = match arg.strip_prefix('-') {
+            Some(v) => resolve(v, &symbols).map(|v| v.wrapping_neg()),
+            None => resolve(arg, &symbols),
+        };
+        match val {
+            Some(v) => vals.push(v),
+            None => {
+                println!("Invalid argument: {}", arg);
+                exit(1);
+            }
+        }
+    }
+
+    let vectors = symbols.addr_of("vectors");
+    let mut cpu = Cpu::new(func);
+    cpu.isa = Isa::for_program(ext, vectors);
+    cpu.vectors = vectors;
+    if let Err(e) = setup(&mut cpu, &mut memory, &phdrs, func, &vals) {
+        println!("{:#}", e);
+        exit(1);
+    }
+
+    let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+    let count = run_call(&mut cpu, &mut memory, &mut hooks, &symbols, trace);
+    if let Err(e) = memory.halt() {
+        println!("{:#}", e);
+    }
+    if cpu.pc != SENTINEL {
+        println!(
+            "{} did not return: stopped with status {} at 0x{:x} after {} instructions",
+            function, cpu.stat, cpu.pc, count
+        );
+        if !trace {
+            dump_cpu(&cpu);
+        }
+        exit(1);
+    }
+    let rax = cpu.reg[Register::RAX as usize];
+    println!(
+        "{} returned 0x{:x} ({}) after {} instructions",
+        function, rax, rax as i64, count
+    );
+
+    exit(0);
+}
+
+/// Resolves a symbol, or else parses a number.
+fn resolve(spec: &str, symbols: &SymTab) -> Option<u64> {
+    symbols.addr_of(spec).or_else(|| parse_num(spec).ok())
+}
+
+/// Reports a file that does not load. Listings say what is wrong and where; Mini-ELF
+/// files keep the original message.
+fn load_failed(file: &str, e: anyhow::Error) -> ! {
+    if file.ends_with(".yo") {
+        println!("{:#}", e);
+        exit(1);
+    }
+    fail();
 }
 
 fn fail() -> ! {
@@ -84,42 +335,114 @@ fn process_args(args: &mut Args) -> bool {
 
 fn main() {
     let mut args = Args::parse();
+    match &args.command {
+        Some(Command::Test { spec, junit }) => run_tests(spec, junit.as_ref()),
+        Some(Command::Call {
+            file,
+            function,
+            args,
+            ext,
+            trace,
+        }) => call_function(file, function, args, ext, *trace),
+        None => (),
+    }
+    let file = args.file.clone().unwrap();
     if !process_args(&mut args) {
         Args::command().print_help().unwrap();
         exit(0);
     }
 
-    let bytes = match std::fs::read(args.file) {
+    // load all segments into virtual memory
+    let mut memory = Memory::new(MEMSIZE as usize);
+    let (hdr, phdrs, symbols) = match load_file(&file, &mut memory.ram) {
         Ok(v) => v,
-        Err(_) => fail(),
+        Err(e) => load_failed(&file, e),
     };
-    let mut reader = Cursor::new(bytes);
 
-    // load the header
-    let hdr = match read_header(&mut reader) {
-        Ok(v) => v,
-        Err(_) => fail(),
+    // override the initial state
+    let mut set_mem = Vec::new();
+    for spec in args.set_mem.iter() {
+        match spec
+            .split_once('=')
+            .and_then(|(addr, val)| Some((resolve(addr, &symbols)?, resolve(val, &symbols)?)))
+        {
+            Some((addr, val)) if memory.store(addr, val) => set_mem.push(addr),
+            _ => {
+                println!("Invalid memory assignment: {}", spec);
+                exit(1);
+            }
+        }
+    }
+    let mut set_regs = Vec::new();
+    for spec in args.set_reg.iter() {
+        match spec
+            .split_once('=')
+            .and_then(|(name, val)| Some((Register::from_name(name)?, resolve(val, &symbols)?)))
+        {
+            Some(v) => set_regs.push(v),
+            None => {
+                println!("Invalid register assignment: {}", spec);
+                exit(1);
+            }
+        }
+    }
+    let entry = match &args.set_pc {
+        Some(spec) => match resolve(spec, &symbols) {
+            Some(v) => v,
+            None => {
+                println!("Unknown start address: {}", spec);
+                exit(1);
+            }
+        },
+        None => hdr.entry as u64,
     };
 
-    // load the program headers
-    let mut phdrs: Vec<ElfPhdr> = Vec::with_capacity(hdr.num_phdr as usize);
-    for i in 0..hdr.num_phdr {
-        let offset: u16 = hdr.phdr_start + size_of::<ElfPhdr>() as u16 * i;
-        let phdr = match read_phdr(&mut reader, offset) {
-            Ok(v) => v,
-            Err(_) => fail(),
-        };
-        phdrs.push(phdr);
+    // map devices over memory
+    for spec in args.device.iter() {
+        let attached = parse_device(spec).and_then(|(addr, dev)| memory.attach(addr, dev));
+        if let Err(e) = attached {
+            println!("{}", e);
+            exit(1);
+        }
     }
 
-    // load all segments into virtual memory
-    let mut memory: Box<[u8]> = Box::new([0; MEMSIZE as usize]);
-    for phdr in phdrs.iter() {
-        if load_segment(&mut reader, &mut memory, phdr).is_err() {
-            fail();
+    // take input from a log or log it
+    if let Some(path) = &args.record {
+        memory.journal = Journal::record(path);
+    }
+    if let Some(path) = &args.replay {
+        match Journal::replay(path) {
+            Ok(v) => memory.journal = v,
+            Err(e) => {
+                println!("{:#}", e);
+                exit(1);
+            }
         }
     }
 
+    let vectors = match &args.vectors {
+        Some(spec) => match symbols.addr_of(spec) {
+            Some(v) => Some(v),
+            None => match parse_num(spec) {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    println!("Unknown vector table address: {}", spec);
+                    exit(1);
+                }
+            },
+        },
+        None => symbols.addr_of("vectors"),
+    };
+    if vectors.is_some() && (args.seq || args.hcl.is_some()) {
+        println!("The SEQ model does not deliver exceptions or interrupts through a vector table");
+        exit(1);
+    }
+    if args.hcl.is_some() && args.ext.contains(&Ext::SYSCALL) {
+        println!("The HCL model does not perform system calls; use --seq instead");
+        exit(1);
+    }
+    let isa = Isa::for_program(&args.ext, vectors);
+
     if args.hdr {
         dump_header(&hdr);
     }
@@ -128,13 +451,15 @@ fn main() {
         dump_phdrs(&phdrs);
     }
 
-    if args.mem_full {
-        dump_memory(&memory, 0, MEMSIZE);
+    if args.mem_full && dump_memory(&memory, 0, MEMSIZE).is_err() {
+        fail();
     }
 
     if args.mem_brief {
         for phdr in phdrs.iter() {
-            dump_memory(&memory, phdr.vaddr as u16, (phdr.vaddr + phdr.size) as u16);
+            if dump_memory(&memory, phdr.vaddr as u16, (phdr.vaddr + phdr.size) as u16).is_err() {
+                fail();
+            }
         }
     }
 
@@ -142,7 +467,7 @@ fn main() {
         println!("Disassembly of executable contents:");
         for phdr in phdrs.iter() {
             if phdr.ptype == 1 {
-                disassemble_code(&memory, phdr, &hdr);
+                disassemble_code(&memory.ram, phdr, &hdr, &symbols, &isa);
             }
         }
     }
@@ -152,11 +477,223 @@ fn main() {
         for phdr in phdrs.iter() {
             if phdr.ptype == 0 {
                 if phdr.flags == 4 {
-                    disassemble_rodata(&memory, phdr);
+                    disassemble_rodata(&memory.ram, phdr);
                 } else {
-                    disassemble_data(&memory, phdr);
+                    disassemble_data(&memory.ram, phdr, &symbols);
+                }
+            }
+        }
+    }
+
+    if args.stack_usage {
+        analyze_stack(&memory.ram, &hdr, &phdrs, &symbols, &isa);
+    }
+
+    if args.exec
+        || args.exec_trace
+        || args.gdb.is_some()
+        || args.tui
+        || args.seq
+        || args.hcl.is_some()
+    {
+        // further threads start at _start1, _start2, ... and at each --thread
+        let mut threads: Vec<Thread> = start_symbols(&symbols)
+            .into_iter()
+            .map(|entry| Thread { entry, rsp: None })
+            .collect();
+        for spec in args.thread.iter() {
+            match Thread::parse(spec, &symbols) {
+                Ok(v) => threads.push(v),
+                Err(e) => {
+                    println!("{:#}", e);
+                    exit(1);
+                }
+            }
+        }
+        if !threads.is_empty() {
+            if args.gdb.is_some()
+                || args.tui
+                || args.seq
+                || args.hcl.is_some()
+                || !args.break_at.is_empty()
+                || !args.break_if.is_empty()
+                || !args.watch.is_empty()
+            {
+                println!("Multiple threads can only be executed with -e or -E");
+                exit(1);
+            }
+            if args.memcheck || args.check_abi || args.check_stack {
+                println!("--memcheck, --check-abi and --check-stack follow a single thread");
+                exit(1);
+            }
+        }
+
+        let mut hooks: Vec<Box<dyn Hook>> = Vec::new();
+        if let Some(spec) = &args.cache {
+            match Cache::parse(spec) {
+                Ok(mut cache) => {
+                    cache.bypass(&memory);
+                    hooks.push(Box::new(cache))
+                }
+                Err(e) => {
+                    println!("{}", e);
+                    exit(1);
+                }
+            }
+        }
+        for scheme in args.predictor.iter() {
+            hooks.push(Box::new(Predictor::new(*scheme)));
+        }
+        if args.check_stack {
+            match phdrs.iter().find(|p| p.ptype == 2) {
+                Some(stack) => hooks.push(Box::new(StackChecker::new(stack, &symbols))),
+                None => println!("There is no STACK segment to check against"),
+            }
+        }
+        if args.check_abi {
+            hooks.push(Box::new(CallConv::new(hdr.entry as u64, &symbols)));
+        }
+        if args.memcheck {
+            // the overridden state is defined
+            let mut memcheck = Memcheck::new(&memory, &phdrs, &symbols);
+            for (reg, _) in set_regs.iter() {
+                memcheck.define_reg(*reg);
+            }
+            for addr in set_mem.iter() {
+                memcheck.define_mem(*addr, 8);
+            }
+            hooks.push(Box::new(memcheck));
+        }
+
+        let hcl = args.hcl.as_ref().map(|file| {
+            match std::fs::read_to_string(file)
+                .map_err(|e| e.into())
+                .and_then(|text| Hcl::parse(&text))
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("{}: {:#}", file, e);
+                    exit(1);
+                }
+            }
+        });
+
+        let mut debugger = Debugger::new();
+        let added = args
+            .break_at
+            .iter()
+            .try_for_each(|spec| debugger.break_at(spec, &symbols))
+            .and_then(|_| {
+                args.break_if
+                    .iter()
+                    .try_for_each(|spec| debugger.break_if(spec))
+            })
+            .and_then(|_| {
+                args.watch
+                    .iter()
+                    .try_for_each(|spec| debugger.watch(spec, &memory))
+            });
+        if let Err(e) = added {
+            println!("{:#}", e);
+            exit(1);
+        }
+        let debugging =
+            !args.break_at.is_empty() || !args.break_if.is_empty() || !args.watch.is_empty();
+        if debugging && (args.gdb.is_some() || args.tui || args.seq || args.hcl.is_some()) {
+            println!("--break-at, --break-if and --watch can only be used with -e or -E");
+            exit(1);
+        }
+        // the terminal interface reads keys from stdin
+        if args.tui && !io::stdin().is_terminal() {
+            println!("--tui needs a terminal on stdin");
+            exit(1);
+        }
+        let console = memory.regions().any(|(_, _, name)| name == "uart");
+        if args.tui && (console || args.ext.contains(&Ext::SYSCALL)) {
+            println!("--tui cannot share the terminal with a uart or syscall console");
+            exit(1);
+        }
+
+        let mut cpu = Cpu::new(entry);
+        cpu.isa = isa.clone();
+        cpu.vectors = vectors;
+        for (reg, val) in set_regs.iter() {
+            cpu.reg[*reg as usize] = *val;
+        }
+        if !threads.is_empty() {
+            let mut cpus = vec![cpu];
+            for thread in threads.iter() {
+                let mut cpu = Cpu::new(thread.entry);
+                cpu.isa = isa.clone();
+                cpu.vectors = vectors;
+                if let Some(rsp) = thread.rsp {
+                    cpu.reg[Register::RSP as usize] = rsp;
                 }
+                cpus.push(cpu);
+            }
+            for (id, cpu) in cpus.iter().enumerate() {
+                println!("Thread {} beginning execution at 0x{:04x}", id, cpu.pc);
             }
+            let counts = run_threads(
+                &mut cpus,
+                &mut memory,
+                &mut hooks,
+                &symbols,
+                args.schedule,
+                args.seed,
+                args.exec_trace,
+            );
+            if let Err(e) = memory.halt() {
+                println!("{:#}", e);
+            }
+            for (id, cpu) in cpus.iter().enumerate() {
+                println!("Thread {} execution count: {}", id, counts[id]);
+                if !args.exec_trace {
+                    dump_cpu(cpu);
+                }
+            }
+            println!("Total execution count: {}", counts.iter().sum::<u64>());
+
+            for hook in hooks.iter() {
+                hook.report();
+            }
+            return;
+        }
+        println!("Beginning execution at 0x{:04x}", entry);
+        let count = if let Some(addr) = &args.gdb {
+            match serve(addr, &mut cpu, &mut memory, &mut hooks) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("{:#}", e);
+                    exit(1);
+                }
+            }
+        } else if args.seq || hcl.is_some() {
+            run_seq(&mut cpu, &mut memory, &mut hooks, &symbols, hcl.as_ref())
+        } else if args.tui {
+            let mut tui = Tui::new(hdr.entry as u64, &phdrs, &symbols);
+            match tui.run(&mut cpu, &mut memory, &mut hooks) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("{:#}", e);
+                    exit(1);
+                }
+            }
+        } else if debugging {
+            debugger.run(&mut cpu, &mut memory, &mut hooks, &symbols, args.exec_trace)
+        } else {
+            execute(&mut cpu, &mut memory, &mut hooks, &symbols, args.exec_trace)
+        };
+        if let Err(e) = memory.halt() {
+            println!("{:#}", e);
+        }
+        if !args.exec_trace {
+            dump_cpu(&cpu);
+        }
+        println!("Total execution count: {}", count);
+
+        for hook in hooks.iter() {
+            hook.report();
         }
     }
 }
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+
+use crate::{
+    disas::{disassemble, Address, Cpu, Icode, Inst, Opq, Register, Stat, Sys, NUM_REGS},
+    hook::Hook,
+    load::ElfPhdr,
+    memory::Memory,
+    sym::SymTab,
+};
+
+/// Detects reads of uninitialized memory and uses of uninitialized registers. A shadow map
+/// records which bytes of memory are defined: bytes loaded from CODE and DATA segments,
+/// bytes mapped to devices and bytes written during execution. Registers and the
+/// condition codes carry definedness through moves and arithmetic, and a report is made
+/// when an undefined value is used as an operand, an address, a condition or a system
+/// call argument.
+pub struct Memcheck {
+    shadow: Vec<bool>,
+    regs: [bool; NUM_REGS as usize],
+    flags: bool,
+    reads: Vec<bool>, // whether each quad read by the current instruction was fully defined
+    symbols: SymTab,
+    reported: HashSet<(Address, String)>,
+    errors: u64,
+}
+
+impl Memcheck {
+    pub fn new(memory: &Memory, phdrs: &[ElfPhdr], symbols: &SymTab) -> Memcheck {
+        let size = memory.ram.len();
+        let mut shadow = vec![false; size];
+        for phdr in phdrs.iter().filter(|p| p.ptype <= 1) {
+            let start = (phdr.vaddr as usize).min(size);
+            let end = (start + phdr.size as usize).min(size);
+            shadow[start..end].fill(true);
+        }
+        // devices produce their own values
+        for (start, end, _) in memory.regions() {
+            let start = (start as usize).min(size);
+            let end = (end as usize).min(size);
+            shadow[start..end].fill(true);
+        }
+
+        Memcheck {
+            shadow,
+            regs: [false; NUM_REGS as usize],
+            flags: false,
+            reads: Vec::new(),
+            symbols: symbols.clone(),
+            reported: HashSet::new(),
+            errors: 0,
+        }
+    }
+
+    /// Marks a register as defined before execution starts.
+    pub fn define_reg(&mut self, reg: Register) {
+        self.set_reg(reg, true);
+    }
+
+    /// Marks `len` bytes of memory at `addr` as defined before execution starts.
+    pub fn define_mem(&mut self, addr: Address, len: usize) {
+        let start = (addr as usize).min(self.shadow.len());
+        let end = start.saturating_add(len).min(self.shadow.len());
+        self.shadow[start..end].fill(true);
+    }
+
+    fn reg(&self, reg: Register) -> bool {
+        reg == Register::NOREG || self.regs[reg as usize]
+    }
+
+    fn set_reg(&mut self, reg: Register, defined: bool) {
+        if reg != Register::NOREG {
+            self.regs[reg as usize] = defined;
+        }
+    }
+
+    /// Reports a problem once per instruction address and kind.
+    fn error(&mut self, pc: Address, inst: &Inst, what: String) {
+        self.errors += 1;
+        if !self.reported.insert((pc, what.clone())) {
+            return;
+        }
+
+        let location = match self.symbols.lookup(pc) {
+            Some((name, 0)) => format!("0x{:03x} ({})", pc, name),
+            Some((name, offset)) => format!("0x{:03x} ({}+0x{:x})", pc, name, offset),
+            None => format!("0x{:03x}", pc),
+        };
+        println!(
+            "Memcheck: {} at {}: {}",
+            what,
+            location,
+            disassemble(inst, &self.symbols)
+        );
+    }
+
+    /// Reports each undefined register among those used.
+    fn check_regs(&mut self, pc: Address, inst: &Inst, regs: &[Register], what: &str) {
+        for reg in regs.iter() {
+            if !self.reg(*reg) {
+                self.error(pc, inst, format!("{} uninitialized %{}", what, reg));
+            }
+        }
+    }
+}
+
+impl Hook for Memcheck {
+    fn mem_read(&mut self, _pc: Address, addr: Address) {
+        let defined = (addr..addr + 8).all(|a| *self.shadow.get(a as usize).unwrap_or(&true));
+        self.reads.push(defined);
+    }
+
+    fn mem_write(&mut self, _pc: Address, addr: Address) {
+        for a in addr..addr + 8 {
+            if let Some(byte) = self.shadow.get_mut(a as usize) {
+                *byte = true;
+            }
+        }
+    }
+
+    fn after(&mut self, pc: Address, inst: &Inst, cpu: &Cpu) {
+        let reads = std::mem::take(&mut self.reads);
+        if matches!(cpu.stat, Stat::ADR | Stat::INS | Stat::DIV) {
+            return;
+        }
+        let read_defined = reads.iter().all(|d| *d);
+        let rsp = Register::RSP;
+
+        match inst.icode {
+            Icode::CMOV => {
+                if inst.ifun != 0 && !self.flags {
+                    self.error(
+                        pc,
+                        inst,
+                        String::from("Conditional move depends on uninitialized flags"),
+                    );
+                }
+                self.set_reg(inst.rb, self.reg(inst.ra));
+            }
+            Icode::IRMOVQ => self.set_reg(inst.rb, true),
+            Icode::RMMOVQ => {
+                self.check_regs(pc, inst, &[inst.rb], "Address uses");
+                self.check_regs(pc, inst, &[inst.ra], "Store of");
+            }
+            Icode::MRMOVQ => {
+                self.check_regs(pc, inst, &[inst.rb], "Address uses");
+                if !read_defined {
+                    self.error(pc, inst, String::from("Read of uninitialized memory"));
+                }
+                self.set_reg(inst.ra, read_defined);
+            }
+            Icode::OPQ => {
+                // xorq and subq of a register with itself define it regardless of its value
+                let idiom =
+                    inst.ra == inst.rb && matches!(Opq::from(inst.ifun), Opq::XOR | Opq::SUB);
+                if !idiom {
+                    self.check_regs(pc, inst, &[inst.ra, inst.rb], "Operation uses");
+                }
+                let defined = idiom || (self.reg(inst.ra) && self.reg(inst.rb));
+                self.set_reg(inst.rb, defined);
+                self.flags = defined;
+            }
+            Icode::JUMP if inst.ifun != 0 && !self.flags => {
+                self.error(
+                    pc,
+                    inst,
+                    String::from("Conditional jump depends on uninitialized flags"),
+                );
+            }
+            Icode::CALL | Icode::PUSHQ => self.check_regs(pc, inst, &[rsp], "Stack access uses"),
+            Icode::RET => {
+                self.check_regs(pc, inst, &[rsp], "Stack access uses");
+                if !read_defined {
+                    self.error(pc, inst, String::from("Return to uninitialized address"));
+                }
+            }
+            Icode::IRET => {
+                // the flags are popped first, then the pc
+                self.check_regs(pc, inst, &[rsp], "Stack access uses");
+                if !reads.get(1).copied().unwrap_or(true) {
+                    self.error(pc, inst, String::from("Return to uninitialized address"));
+                }
+                self.flags = reads.first().copied().unwrap_or(true);
+                if !self.flags {
+                    self.error(pc, inst, String::from("Restore of uninitialized flags"));
+                }
+            }
+            Icode::POPQ => {
+                self.check_regs(pc, inst, &[rsp], "Stack access uses");
+                if !read_defined {
+                    self.error(pc, inst, String::from("Read of uninitialized memory"));
+                }
+                self.set_reg(inst.ra, read_defined);
+            }
+            Icode::IADDQ => {
+                self.check_regs(pc, inst, &[inst.rb], "Operation uses");
+                self.flags = self.reg(inst.rb);
+            }
+            Icode::LEAVE => {
+                self.check_regs(pc, inst, &[Register::RBP], "Stack access uses");
+                if !read_defined {
+                    self.error(pc, inst, String::from("Read of uninitialized memory"));
+                }
+                self.set_reg(rsp, self.reg(Register::RBP));
+                self.set_reg(Register::RBP, read_defined);
+            }
+            Icode::CAS => {
+                self.check_regs(pc, inst, &[inst.rb], "Address uses");
+                self.check_regs(pc, inst, &[Register::RAX], "Comparison uses");
+                if !read_defined {
+                    self.error(pc, inst, String::from("Read of uninitialized memory"));
+                }
+                self.flags = read_defined && self.reg(Register::RAX);
+                // %rax is loaded from memory only when the comparison fails
+                if cpu.zf {
+                    self.check_regs(pc, inst, &[inst.ra], "Store of");
+                } else {
+                    self.set_reg(Register::RAX, read_defined);
+                }
+            }
+            Icode::SYSCALL => match Sys::from(inst.ifun) {
+                Sys::WRCH | Sys::WRINT => {
+                    self.check_regs(pc, inst, &[Register::RDI], "System call uses")
+                }
+                Sys::RDINT => self.set_reg(Register::RAX, true),
+                Sys::EXIT => (),
+            },
+            _ => (),
+        }
+    }
+
+    fn report(&self) {
+        println!("Memcheck: {} error(s)", self.errors);
+    }
+}
@@ -0,0 +1,137 @@
+use anyhow::{ensure, Context, Result};
+
+use crate::{
+    device::Device,
+    disas::{memtoi, Address},
+    journal::Journal,
+};
+
+struct Mapping {
+    start: Address,
+    end: Address,
+    device: Box<dyn Device>,
+}
+
+/// Simulated memory: a block of RAM with devices mapped over address ranges. Data loads
+/// and stores made during execution go through here so they can be routed to devices.
+pub struct Memory {
+    pub ram: Box<[u8]>,
+    devices: Vec<Mapping>,
+    /// The console and other outside input and output of the program
+    pub journal: Journal,
+}
+
+impl Memory {
+    pub fn new(size: usize) -> Memory {
+        Memory {
+            ram: vec![0u8; size].into_boxed_slice(),
+            devices: Vec::new(),
+            journal: Journal::default(),
+        }
+    }
+
+    pub fn attach(&mut self, start: Address, device: Box<dyn Device>) -> Result<()> {
+        let end = start.checked_add(device.size()).with_context(|| {
+            format!(
+                "Device {} at 0x{:x} is outside the address space",
+                device.name(),
+                start
+            )
+        })?;
+        ensure!(
+            self.devices
+                .iter()
+                .all(|m| end <= m.start || start >= m.end),
+            "Device {} overlaps another device",
+            device.name()
+        );
+        self.devices.push(Mapping { start, end, device });
+
+        Ok(())
+    }
+
+    /// Loads a quad, returning None if the address is invalid.
+    pub fn load(&mut self, addr: Address) -> Option<Address> {
+        match self.device_index(addr) {
+            Some(Some(i)) => {
+                let m = &mut self.devices[i];
+                Some(m.device.read(addr - m.start, &mut self.journal))
+            }
+            Some(None) => memtoi(&self.ram, addr),
+            None => None,
+        }
+    }
+
+    /// Stores a quad, returning false if the address is invalid.
+    pub fn store(&mut self, addr: Address, val: Address) -> bool {
+        match self.device_index(addr) {
+            Some(Some(i)) => {
+                let m = &mut self.devices[i];
+                m.device.write(addr - m.start, val, &mut self.journal);
+                true
+            }
+            Some(None) => {
+                let end = match addr.checked_add(8) {
+                    Some(v) if v <= self.ram.len() as u64 => v,
+                    _ => return false,
+                };
+                self.ram[addr as usize..end as usize].copy_from_slice(&val.to_le_bytes());
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        self.journal.tick();
+        for m in self.devices.iter_mut() {
+            m.device.tick();
+        }
+    }
+
+    /// Returns true if a device raises an interrupt.
+    pub fn interrupt(&mut self) -> bool {
+        let devices = &mut self.devices;
+        self.journal
+            .interrupt(|| devices.iter_mut().any(|m| m.device.interrupt()))
+    }
+
+    pub fn halt(&mut self) -> Result<()> {
+        for m in self.devices.iter_mut() {
+            m.device.halt()?;
+        }
+
+        self.journal.finish()
+    }
+
+    /// Returns the name of the device mapped at `addr`, if any.
+    pub fn device_at(&self, addr: Address) -> Option<&str> {
+        self.devices
+            .iter()
+            .find(|m| addr >= m.start && addr < m.end)
+            .map(|m| m.device.name())
+    }
+
+    /// Returns the start, end and name of every device region.
+    pub fn regions(&self) -> impl Iterator<Item = (Address, Address, &str)> {
+        self.devices
+            .iter()
+            .map(|m| (m.start, m.end, m.device.name()))
+    }
+
+    /// Finds which device a quad access at `addr` goes to. Returns `Some(None)` for RAM and
+    /// None for an access straddling a device boundary.
+    fn device_index(&self, addr: Address) -> Option<Option<usize>> {
+        let end = addr.checked_add(8)?;
+        for (i, m) in self.devices.iter().enumerate() {
+            if addr >= m.start && end <= m.end {
+                return Some(Some(i));
+            }
+            if addr < m.end && end > m.start {
+                return None;
+            }
+        }
+
+        Some(None)
+    }
+}
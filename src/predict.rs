@@ -0,0 +1,138 @@
+use std::collections::BTreeMap;
+
+use clap::ValueEnum;
+
+use crate::{disas::Address, hook::Hook};
+
+/// Number of index bits for the predictor tables and the gshare history.
+const TABLE_BITS: u32 = 10;
+
+#[derive(ValueEnum, PartialEq, Clone, Copy, Debug)]
+pub enum Scheme {
+    /// Always predict taken
+    #[value(name = "taken")]
+    TAKEN,
+    /// Backward taken, forward not taken
+    #[value(name = "btfnt")]
+    BTFNT,
+    /// Last outcome per branch
+    #[value(name = "1bit")]
+    ONEBIT,
+    /// Two-bit saturating counter per branch
+    #[value(name = "2bit")]
+    TWOBIT,
+    /// Two-bit counters indexed by pc xor global history
+    #[value(name = "gshare")]
+    GSHARE,
+}
+
+#[derive(Default)]
+struct Stats {
+    count: u64,
+    taken: u64,
+    correct: u64,
+}
+
+/// Branch predictor model for conditional jumps. Predictions are checked against the
+/// resolved outcome and accuracy is tracked per branch.
+pub struct Predictor {
+    scheme: Scheme,
+    table: Vec<u8>,
+    history: u64,
+    stats: BTreeMap<Address, Stats>,
+}
+
+impl Predictor {
+    pub fn new(scheme: Scheme) -> Predictor {
+        // counters start weakly not taken
+        let init = match scheme {
+            Scheme::TWOBIT | Scheme::GSHARE => 1,
+            _ => 0,
+        };
+
+        Predictor {
+            scheme,
+            table: vec![init; 1 << TABLE_BITS],
+            history: 0,
+            stats: BTreeMap::new(),
+        }
+    }
+
+    fn index(&self, pc: Address) -> usize {
+        let mask = (1 << TABLE_BITS) - 1;
+        match self.scheme {
+            Scheme::GSHARE => ((pc ^ self.history) & mask) as usize,
+            _ => (pc & mask) as usize,
+        }
+    }
+
+    fn predict(&self, pc: Address, target: Address) -> bool {
+        match self.scheme {
+            Scheme::TAKEN => true,
+            Scheme::BTFNT => target <= pc,
+            Scheme::ONEBIT => self.table[self.index(pc)] == 1,
+            Scheme::TWOBIT | Scheme::GSHARE => self.table[self.index(pc)] >= 2,
+        }
+    }
+
+    fn update(&mut self, pc: Address, taken: bool) {
+        let i = self.index(pc);
+        match self.scheme {
+            Scheme::TAKEN | Scheme::BTFNT => (),
+            Scheme::ONEBIT => self.table[i] = taken as u8,
+            Scheme::TWOBIT | Scheme::GSHARE => {
+                if taken {
+                    self.table[i] = (self.table[i] + 1).min(3);
+                } else {
+                    self.table[i] = self.table[i].saturating_sub(1);
+                }
+            }
+        }
+        self.history = (self.history << 1 | taken as u64) & ((1 << TABLE_BITS) - 1);
+    }
+}
+
+impl Hook for Predictor {
+    fn branch(&mut self, pc: Address, target: Address, taken: bool) {
+        let correct = self.predict(pc, target) == taken;
+        self.update(pc, taken);
+
+        let stats = self.stats.entry(pc).or_default();
+        stats.count += 1;
+        stats.taken += taken as u64;
+        stats.correct += correct as u64;
+    }
+
+    fn report(&self) {
+        let name = self.scheme.to_possible_value().unwrap();
+        println!("Branch predictor: {}", name.get_name());
+        println!("  PC       Count    Taken    Correct  Accuracy");
+
+        let mut total = Stats::default();
+        for (pc, stats) in self.stats.iter() {
+            println!(
+                "  0x{:03x}    {:<8} {:<8} {:<8} {:.2}%",
+                pc,
+                stats.count,
+                stats.taken,
+                stats.correct,
+                stats.correct as f64 * 100.0 / stats.count as f64
+            );
+            total.count += stats.count;
+            total.taken += stats.taken;
+            total.correct += stats.correct;
+        }
+
+        if total.count == 0 {
+            println!("  No conditional branches executed");
+        } else {
+            println!(
+                "  Total    {:<8} {:<8} {:<8} {:.2}%",
+                total.count,
+                total.taken,
+                total.correct,
+                total.correct as f64 * 100.0 / total.count as f64
+            );
+        }
+    }
+}
@@ -0,0 +1,374 @@
+use crate::{
+    disas::{disassemble, fetch, Address, Cpu, Icode, Inst, Jump, Opq, Register, Stat},
+    exec::{alu, cond, get_reg, load, set_reg, store, syscall},
+    hcl::Hcl,
+    hook::Hook,
+    memory::Memory,
+    sym::SymTab,
+};
+
+/// The named signals of one cycle of the CS:APP SEQ processor.
+pub struct Signals {
+    pub pc: Address,
+    pub inst: Inst,
+    pub icode: u8,
+    pub ifun: u8,
+    pub imem_error: bool,
+    pub instr_valid: bool,
+    pub need_regids: bool,
+    pub need_val_c: bool,
+    pub src_a: Register,
+    pub src_b: Register,
+    pub dst_e: Register,
+    pub dst_m: Register,
+    pub val_a: Address,
+    pub val_b: Address,
+    pub alu_a: Address,
+    pub alu_b: Address,
+    pub alu_fun: u8,
+    pub set_cc: bool,
+    pub val_e: Address,
+    pub cnd: bool,
+    pub mem_read: bool,
+    pub mem_write: bool,
+    pub mem_addr: Address,
+    pub mem_data: Address,
+    pub val_m: Address,
+    pub dmem_error: bool,
+    pub stat: Stat,
+    pub new_pc: Address,
+}
+
+impl Signals {
+    /// Signals at the start of a cycle, before anything has been decoded.
+    pub fn new(pc: Address, inst: Inst) -> Signals {
+        Signals {
+            pc,
+            inst,
+            icode: 0,
+            ifun: 0,
+            imem_error: false,
+            instr_valid: true,
+            need_regids: false,
+            need_val_c: false,
+            src_a: Register::NOREG,
+            src_b: Register::NOREG,
+            dst_e: Register::NOREG,
+            dst_m: Register::NOREG,
+            val_a: 0,
+            val_b: 0,
+            alu_a: 0,
+            alu_b: 0,
+            alu_fun: 0,
+            set_cc: false,
+            val_e: 0,
+            cnd: false,
+            mem_read: false,
+            mem_write: false,
+            mem_addr: 0,
+            mem_data: 0,
+            val_m: 0,
+            dmem_error: false,
+            stat: Stat::AOK,
+            new_pc: pc,
+        }
+    }
+}
+
+/// Runs the program one SEQ cycle at a time until the cpu leaves the AOK state, printing
+/// every signal of each stage. The control signals come from `hcl` if given, otherwise
+/// from the built-in Y86-64 control logic. Returns the number of instructions completed,
+/// which does not include a final cycle that failed to fetch.
+pub fn run_seq(
+    cpu: &mut Cpu,
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    symbols: &SymTab,
+    hcl: Option<&Hcl>,
+) -> u64 {
+    let mut count = 0;
+
+    while cpu.stat == Stat::AOK {
+        let signals = match hcl {
+            Some(hcl) => match hcl.cycle(cpu, memory, hooks) {
+                Ok(v) => v,
+                Err(e) => {
+                    println!("\n{:#}", e);
+                    break;
+                }
+            },
+            None => cycle(cpu, memory, hooks),
+        };
+
+        println!("\nCycle {}:", count + 1);
+        print_signals(&signals, symbols);
+        if signals.imem_error || !signals.instr_valid {
+            break;
+        }
+        count += 1;
+    }
+
+    println!();
+    count
+}
+
+/// Performs one cycle of the fetch, decode, execute, memory, write-back and PC update
+/// stages. An instruction that fails to fetch stops the cycle in the fetch stage.
+pub fn cycle(cpu: &mut Cpu, memory: &mut Memory, hooks: &mut [Box<dyn Hook>]) -> Signals {
+    // fetch
+    let pc = cpu.pc;
+    let byte = memory.ram.get(pc as usize).copied().unwrap_or(0);
+    let inst = fetch(cpu, &memory.ram);
+    let mut s = Signals::new(pc, inst);
+    s.icode = byte >> 4;
+    s.ifun = byte & 0xf;
+    s.stat = cpu.stat;
+    if s.inst.icode == Icode::INVALID {
+        s.imem_error = s.stat == Stat::ADR;
+        s.instr_valid = s.stat != Stat::INS;
+        return s;
+    }
+    if matches!(s.inst.icode, Icode::IRET | Icode::CAS | Icode::EXT(_)) {
+        // the control logic only covers the built-in instructions
+        s.instr_valid = false;
+        s.stat = Stat::INS;
+        cpu.stat = Stat::INS;
+        return s;
+    }
+    s.need_regids = matches!(s.inst.val_p - pc, 2 | 10);
+    s.need_val_c = s.inst.val_c.is_some();
+    let icode = &s.inst.icode;
+    let val_c = s.inst.val_c.unwrap_or(0);
+
+    // decode
+    s.src_a = match icode {
+        Icode::CMOV | Icode::RMMOVQ | Icode::OPQ | Icode::PUSHQ => s.inst.ra,
+        Icode::POPQ | Icode::RET => Register::RSP,
+        Icode::LEAVE => Register::RBP,
+        Icode::SYSCALL => Register::RDI,
+        _ => Register::NOREG,
+    };
+    s.src_b = match icode {
+        Icode::OPQ | Icode::RMMOVQ | Icode::MRMOVQ | Icode::IADDQ => s.inst.rb,
+        Icode::LEAVE => Register::RBP,
+        Icode::PUSHQ | Icode::POPQ | Icode::CALL | Icode::RET => Register::RSP,
+        _ => Register::NOREG,
+    };
+    s.dst_m = match icode {
+        Icode::MRMOVQ | Icode::POPQ => s.inst.ra,
+        Icode::LEAVE => Register::RBP,
+        _ => Register::NOREG,
+    };
+    s.val_a = get_reg(cpu, s.src_a);
+    s.val_b = get_reg(cpu, s.src_b);
+
+    // execute
+    s.alu_a = match icode {
+        Icode::CMOV | Icode::OPQ => s.val_a,
+        Icode::IRMOVQ | Icode::RMMOVQ | Icode::MRMOVQ | Icode::IADDQ => val_c,
+        Icode::CALL | Icode::PUSHQ => -8i64 as Address,
+        Icode::RET | Icode::POPQ | Icode::LEAVE => 8,
+        _ => 0,
+    };
+    s.alu_b = match icode {
+        Icode::RMMOVQ
+        | Icode::MRMOVQ
+        | Icode::OPQ
+        | Icode::CALL
+        | Icode::PUSHQ
+        | Icode::RET
+        | Icode::POPQ
+        | Icode::IADDQ
+        | Icode::LEAVE => s.val_b,
+        _ => 0,
+    };
+    s.set_cc = matches!(icode, Icode::OPQ | Icode::IADDQ);
+    if *icode == Icode::OPQ {
+        s.alu_fun = s.inst.ifun;
+    }
+    s.val_e = if s.set_cc {
+        alu(cpu, s.inst.ifun, s.alu_a, s.alu_b)
+    } else {
+        s.alu_b.wrapping_add(s.alu_a)
+    };
+    if cpu.stat == Stat::DIV {
+        // a zero divisor stops the instruction before memory and write-back
+        s.stat = Stat::DIV;
+        return s;
+    }
+    s.cnd = match icode {
+        Icode::JUMP | Icode::CMOV => cond(cpu, s.inst.ifun),
+        _ => false,
+    };
+    s.dst_e = match icode {
+        Icode::CMOV if s.cnd => s.inst.rb,
+        Icode::IRMOVQ | Icode::OPQ | Icode::IADDQ => s.inst.rb,
+        Icode::PUSHQ | Icode::POPQ | Icode::CALL | Icode::RET | Icode::LEAVE => Register::RSP,
+        _ => Register::NOREG,
+    };
+    if *icode == Icode::JUMP && !matches!(Jump::from(s.inst.ifun), Jump::JMP) {
+        for hook in hooks.iter_mut() {
+            hook.branch(pc, val_c, s.cnd);
+        }
+    }
+
+    // memory
+    s.mem_read = matches!(
+        icode,
+        Icode::MRMOVQ | Icode::POPQ | Icode::RET | Icode::LEAVE
+    );
+    s.mem_write = matches!(icode, Icode::RMMOVQ | Icode::PUSHQ | Icode::CALL);
+    s.mem_addr = match icode {
+        Icode::RMMOVQ | Icode::PUSHQ | Icode::CALL | Icode::MRMOVQ => s.val_e,
+        Icode::POPQ | Icode::RET | Icode::LEAVE => s.val_a,
+        _ => 0,
+    };
+    s.mem_data = match icode {
+        Icode::RMMOVQ | Icode::PUSHQ => s.val_a,
+        Icode::CALL => s.inst.val_p,
+        _ => 0,
+    };
+    let mut dmem_error = false;
+    if s.mem_read {
+        match load(cpu, memory, hooks, s.mem_addr) {
+            Some(v) => s.val_m = v,
+            None => dmem_error = true,
+        }
+    }
+    if s.mem_write && !store(cpu, memory, hooks, s.mem_addr, s.mem_data) {
+        dmem_error = true;
+    }
+    if dmem_error {
+        s.dmem_error = true;
+        s.stat = Stat::ADR;
+        cpu.stat = Stat::ADR;
+        return s;
+    }
+
+    // write-back, with valM taking priority when both ports name the same register
+    set_reg(cpu, s.dst_e, s.val_e);
+    set_reg(cpu, s.dst_m, s.val_m);
+    if *icode == Icode::SYSCALL {
+        syscall(cpu, memory, s.inst.ifun);
+    }
+    s.stat = cpu.stat;
+
+    // PC update; a halted processor keeps pointing at the halt
+    s.new_pc = match icode {
+        Icode::CALL => val_c,
+        Icode::JUMP if s.cnd => val_c,
+        Icode::RET => s.val_m,
+        _ if s.stat == Stat::HLT => pc,
+        _ => s.inst.val_p,
+    };
+    cpu.pc = s.new_pc;
+
+    for hook in hooks.iter_mut() {
+        hook.after(pc, &s.inst, cpu);
+    }
+    memory.tick();
+
+    s
+}
+
+fn print_signals(s: &Signals, symbols: &SymTab) {
+    if s.imem_error || !s.instr_valid {
+        println!(
+            "  fetch:      PC=0x{:03x} icode={:x} ifun={:x} instr_valid={} imem_error={}",
+            s.pc, s.icode, s.ifun, s.instr_valid as u8, s.imem_error as u8
+        );
+        println!("  Stat={}", s.stat);
+        return;
+    }
+
+    match s.inst.icode {
+        Icode::INVALID => println!("  0x{:03x}: (icode {:x} has no mnemonic)", s.pc, s.icode),
+        _ => println!("  0x{:03x}: {}", s.pc, disassemble(&s.inst, symbols)),
+    }
+    println!(
+        "  fetch:      icode={:x} ifun={:x} rA={} rB={} valC={} valP={}",
+        s.icode,
+        s.ifun,
+        reg(s.inst.ra),
+        reg(s.inst.rb),
+        match s.inst.val_c {
+            Some(v) => num(v),
+            None => "-".to_string(),
+        },
+        num(s.inst.val_p)
+    );
+    println!(
+        "              need_regids={} need_valC={} instr_valid={} imem_error={}",
+        s.need_regids as u8, s.need_val_c as u8, s.instr_valid as u8, s.imem_error as u8
+    );
+    println!(
+        "  decode:     srcA={} srcB={} dstE={} dstM={} valA={} valB={}",
+        reg(s.src_a),
+        reg(s.src_b),
+        reg(s.dst_e),
+        reg(s.dst_m),
+        num(s.val_a),
+        num(s.val_b)
+    );
+    let alu_fun = match Opq::from(s.alu_fun) {
+        Opq::ADD => "+",
+        Opq::SUB => "-",
+        Opq::AND => "&",
+        Opq::XOR => "^",
+        Opq::MUL => "*",
+        Opq::DIV => "/",
+        Opq::MOD => "%",
+        Opq::SHL => "<<",
+        Opq::SAR => ">>",
+        Opq::SHR => ">>>",
+        Opq::OR => "|",
+    };
+    println!(
+        "  execute:    aluA={} aluB={} alufun={} set_cc={} valE={} Cnd={}",
+        num(s.alu_a),
+        num(s.alu_b),
+        alu_fun,
+        s.set_cc as u8,
+        num(s.val_e),
+        s.cnd as u8
+    );
+    println!(
+        "  memory:     mem_read={} mem_write={} mem_addr={} mem_data={} valM={} dmem_error={}",
+        s.mem_read as u8,
+        s.mem_write as u8,
+        num(s.mem_addr),
+        num(s.mem_data),
+        num(s.val_m),
+        s.dmem_error as u8
+    );
+    println!("  status:     Stat={}", s.stat);
+    let write = |dst: Register, val: Address| match dst {
+        Register::NOREG => "none".to_string(),
+        _ => format!("{} <- {}", reg(dst), num(val)),
+    };
+    if s.dmem_error {
+        println!("  write-back: none");
+    } else {
+        println!(
+            "  write-back: E: {}  M: {}",
+            write(s.dst_e, s.val_e),
+            write(s.dst_m, s.val_m)
+        );
+    }
+    println!("  pc update:  newPC={}", num(s.new_pc));
+}
+
+fn reg(reg: Register) -> String {
+    match reg {
+        Register::NOREG => "none".to_string(),
+        _ => format!("%{}", reg),
+    }
+}
+
+/// Shows a value as hex, with negative values signed so that `-8` reads naturally.
+fn num(val: Address) -> String {
+    match val as i64 {
+        v if v < 0 => format!("-0x{:x}", v.unsigned_abs()),
+        v => format!("0x{:x}", v),
+    }
+}
@@ -0,0 +1,114 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use crate::{
+    disas::{disassemble, Address, Cpu, Stat},
+    exec::{dump_cpu, step},
+    hook::Hook,
+    memory::Memory,
+    parse_num,
+    sym::SymTab,
+};
+
+/// How the instructions of the threads are interleaved.
+#[derive(ValueEnum, PartialEq, Clone, Copy, Debug)]
+pub enum Schedule {
+    /// Each running thread executes one instruction in turn
+    #[value(name = "rr")]
+    RR,
+    /// A running thread picked by a seeded generator executes the next instruction
+    #[value(name = "random")]
+    RANDOM,
+}
+
+/// Where a thread starts, from `entry=ADDR[,rsp=ADDR]`.
+pub struct Thread {
+    pub entry: Address,
+    pub rsp: Option<Address>,
+}
+
+impl Thread {
+    /// Parses a thread spec. Addresses may be numbers or symbols.
+    pub fn parse(spec: &str, symbols: &SymTab) -> Result<Thread> {
+        let (mut entry, mut rsp) = (None, None);
+
+        for field in spec.split(',').filter(|f| !f.is_empty()) {
+            let (key, val) = field.split_once('=').context("Expected key=value")?;
+            let addr = match symbols.addr_of(val) {
+                Some(v) => v,
+                None => parse_num(val)?,
+            };
+            match key {
+                "entry" => entry = Some(addr),
+                "rsp" => rsp = Some(addr),
+                _ => bail!("Unknown thread option: {}", key),
+            }
+        }
+
+        Ok(Thread {
+            entry: entry.context("Expected entry=ADDR")?,
+            rsp,
+        })
+    }
+}
+
+/// Entry points of the threads named by the symbols `_start1`, `_start2` and so on, up to
+/// the first number that is missing.
+pub fn start_symbols(symbols: &SymTab) -> Vec<Address> {
+    (1..)
+        .map_while(|n| symbols.addr_of(&format!("_start{}", n)))
+        .collect()
+}
+
+/// Runs threads sharing one memory until every one has left the AOK state. A thread that
+/// halts or faults stops on its own while the others carry on. Returns the number of
+/// instructions each thread executed. In trace mode every instruction is printed with
+/// the number of the thread executing it.
+pub fn run_threads(
+    cpus: &mut [Cpu],
+    memory: &mut Memory,
+    hooks: &mut [Box<dyn Hook>],
+    symbols: &SymTab,
+    schedule: Schedule,
+    seed: u64,
+    trace: bool,
+) -> Vec<u64> {
+    let mut counts = vec![0; cpus.len()];
+    let mut last = cpus.len() - 1;
+    // xorshift keeps runs reproducible; the state must not be zero
+    let mut rng = seed.wrapping_mul(0x9e3779b97f4a7c15) | 1;
+
+    loop {
+        let running: Vec<usize> = (0..cpus.len())
+            .filter(|i| cpus[*i].stat == Stat::AOK)
+            .collect();
+        if running.is_empty() {
+            break;
+        }
+
+        let id = match schedule {
+            Schedule::RR => *running.iter().find(|i| **i > last).unwrap_or(&running[0]),
+            Schedule::RANDOM => {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                running[(rng % running.len() as u64) as usize]
+            }
+        };
+        last = id;
+
+        let cpu = &mut cpus[id];
+        let inst = match step(cpu, memory, hooks) {
+            Some(v) => v,
+            None => continue,
+        };
+        counts[id] += 1;
+
+        if trace {
+            println!("\nThread {} executing: {}", id, disassemble(&inst, symbols));
+            dump_cpu(cpu);
+        }
+    }
+
+    counts
+}
@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+    check::ElfHdr,
+    disas::{fetch, Address, Cpu, Icode, Inst, Jump, Opq, Register, Stat, Sys, NUM_REGS},
+    hook::Hook,
+    isa::Isa,
+    load::ElfPhdr,
+    sym::SymTab,
+};
+
+/// Stack behavior of one function found by scanning its code.
+struct Function {
+    frame: Option<i64>,         // deepest push depth, None if unbounded
+    calls: Vec<(Address, i64)>, // callee and the depth at the call
+    unknown: Vec<Address>,      // instructions adjusting %rsp by an unknown amount
+}
+
+/// Scanner state along one path through a function.
+#[derive(Clone)]
+struct Path {
+    pc: Address,
+    depth: i64,
+    consts: [Option<u64>; NUM_REGS as usize],
+    rsp_copy: [Option<i64>; NUM_REGS as usize], // depth saved by rrmovq %rsp, %r
+}
+
+/// Scans every path through the function at `entry`. Depth is the number of bytes pushed
+/// since entry, not counting the return address.
+fn scan(memory: &[u8], entry: Address, isa: &Isa) -> Function {
+    let mut func = Function {
+        frame: Some(0),
+        calls: Vec::new(),
+        unknown: Vec::new(),
+    };
+    let mut visited: HashMap<Address, i64> = HashMap::new();
+    let mut work = vec![Path {
+        pc: entry,
+        depth: 0,
+        consts: [None; NUM_REGS as usize],
+        rsp_copy: [None; NUM_REGS as usize],
+    }];
+
+    while let Some(mut path) = work.pop() {
+        match visited.get(&path.pc) {
+            Some(depth) if path.depth > *depth => {
+                // a loop that keeps pushing has no bound
+                func.frame = None;
+                continue;
+            }
+            Some(_) => continue,
+            None => {
+                visited.insert(path.pc, path.depth);
+            }
+        }
+
+        let mut cpu = Cpu::new(path.pc);
+        cpu.isa = isa.clone();
+        let inst = fetch(&mut cpu, memory);
+        if inst.icode == Icode::INVALID {
+            continue;
+        }
+
+        step(&mut path, &inst, &mut func);
+        if let Some(frame) = func.frame {
+            func.frame = Some(frame.max(path.depth));
+        }
+
+        match inst.icode {
+            Icode::HALT | Icode::RET | Icode::IRET => (),
+            Icode::SYSCALL if matches!(Sys::from(inst.ifun), Sys::EXIT) => (),
+            Icode::JUMP => {
+                if !matches!(Jump::from(inst.ifun), Jump::JMP) {
+                    let mut fall = path.clone();
+                    fall.pc = inst.val_p;
+                    work.push(fall);
+                }
+                path.pc = inst.val_c.unwrap();
+                work.push(path);
+            }
+            _ => {
+                path.pc = inst.val_p;
+                work.push(path);
+            }
+        }
+    }
+
+    func
+}
+
+/// Applies the stack effect of one instruction to a path.
+fn step(path: &mut Path, inst: &Inst, func: &mut Function) {
+    match inst.icode {
+        Icode::PUSHQ => path.depth += 8,
+        Icode::POPQ => {
+            path.depth -= 8;
+            forget(path, inst.ra);
+        }
+        Icode::CALL => func.calls.push((inst.val_c.unwrap(), path.depth)),
+        Icode::IRMOVQ => {
+            if inst.rb == Register::RSP {
+                // the stack pointer is being set up, so start counting again
+                path.depth = 0;
+            } else {
+                forget(path, inst.rb);
+                path.consts[inst.rb as usize] = inst.val_c;
+            }
+        }
+        Icode::CMOV if inst.rb == Register::RSP => match path.rsp_copy[inst.ra as usize] {
+            Some(depth) if inst.ifun == 0 => path.depth = depth,
+            _ => func.unknown.push(path.pc),
+        },
+        Icode::CMOV => {
+            forget(path, inst.rb);
+            if inst.ifun == 0 {
+                if inst.ra == Register::RSP {
+                    path.rsp_copy[inst.rb as usize] = Some(path.depth);
+                } else {
+                    path.consts[inst.rb as usize] = path.consts[inst.ra as usize];
+                }
+            }
+        }
+        Icode::OPQ if inst.rb == Register::RSP => {
+            match (Opq::from(inst.ifun), path.consts[inst.ra as usize]) {
+                (Opq::SUB, Some(v)) => path.depth += v as i64,
+                (Opq::ADD, Some(v)) => path.depth -= v as i64,
+                _ => func.unknown.push(path.pc),
+            }
+        }
+        Icode::OPQ => forget(path, inst.rb),
+        Icode::MRMOVQ => {
+            if inst.ra == Register::RSP {
+                func.unknown.push(path.pc);
+            }
+            forget(path, inst.ra);
+        }
+        Icode::IADDQ if inst.rb == Register::RSP => {
+            path.depth -= inst.val_c.unwrap() as i64;
+        }
+        Icode::IADDQ => {
+            let value = path.consts[inst.rb as usize].map(|v| v.wrapping_add(inst.val_c.unwrap()));
+            forget(path, inst.rb);
+            path.consts[inst.rb as usize] = value;
+        }
+        Icode::LEAVE => {
+            match path.rsp_copy[Register::RBP as usize] {
+                Some(depth) => path.depth = depth - 8,
+                None => func.unknown.push(path.pc),
+            }
+            forget(path, Register::RBP);
+        }
+        // rdint returns in %rax, and a failed cas loads the old value into it
+        Icode::SYSCALL | Icode::CAS => forget(path, Register::RAX),
+        _ => (),
+    }
+}
+
+fn forget(path: &mut Path, reg: Register) {
+    if reg != Register::NOREG {
+        path.consts[reg as usize] = None;
+        path.rsp_copy[reg as usize] = None;
+    }
+}
+
+/// Worst-case stack usage of each function including its callees. None means unbounded.
+fn worst(
+    f: Address,
+    funcs: &BTreeMap<Address, Function>,
+    memo: &mut HashMap<Address, Option<i64>>,
+    active: &mut Vec<Address>,
+    cycles: &mut Vec<Vec<Address>>,
+) -> Option<i64> {
+    if let Some(i) = active.iter().position(|a| *a == f) {
+        cycles.push(active[i..].to_vec());
+        return None;
+    }
+    if let Some(v) = memo.get(&f) {
+        return *v;
+    }
+
+    active.push(f);
+    let func = &funcs[&f];
+    let mut usage = func.frame;
+    for (callee, depth) in func.calls.iter() {
+        let sub = worst(*callee, funcs, memo, active, cycles);
+        usage = match (usage, sub) {
+            (Some(u), Some(s)) => Some(u.max(depth + 8 + s)),
+            _ => None,
+        };
+    }
+    active.pop();
+
+    memo.insert(f, usage);
+    usage
+}
+
+/// Builds the call graph from the entry point and prints the worst-case stack usage of each
+/// function, warning about recursion and programs that may overflow the STACK segment.
+pub fn analyze_stack(memory: &[u8], hdr: &ElfHdr, phdrs: &[ElfPhdr], symbols: &SymTab, isa: &Isa) {
+    let name = |addr: Address| match symbols.name_at(addr) {
+        Some(name) => format!("{} (0x{:03x})", name, addr),
+        None if addr == hdr.entry as Address => format!("_start (0x{:03x})", addr),
+        None => format!("0x{:03x}", addr),
+    };
+
+    // discover every function reachable through calls
+    let mut funcs: BTreeMap<Address, Function> = BTreeMap::new();
+    let mut work = vec![hdr.entry as Address];
+    while let Some(f) = work.pop() {
+        if funcs.contains_key(&f) {
+            continue;
+        }
+        let func = scan(memory, f, isa);
+        work.extend(func.calls.iter().map(|(callee, _)| *callee));
+        funcs.insert(f, func);
+    }
+
+    println!("Call graph:");
+    for (f, func) in funcs.iter() {
+        let mut callees: Vec<Address> = func.calls.iter().map(|(c, _)| *c).collect();
+        callees.sort();
+        callees.dedup();
+        let callees: Vec<String> = callees.into_iter().map(name).collect();
+        if callees.is_empty() {
+            println!("  {}", name(*f));
+        } else {
+            println!("  {} -> {}", name(*f), callees.join(", "));
+        }
+    }
+
+    let mut memo = HashMap::new();
+    let mut cycles = Vec::new();
+    for f in funcs.keys() {
+        worst(*f, &funcs, &mut memo, &mut Vec::new(), &mut cycles);
+    }
+    let fmt = |v: Option<i64>| match v {
+        Some(v) => v.to_string(),
+        None => String::from("unbounded"),
+    };
+
+    println!("Stack usage (bytes, excluding the return address):");
+    println!("  Function                 Frame      Worst case");
+    for (f, func) in funcs.iter() {
+        println!(
+            "  {:<24} {:<10} {}",
+            name(*f),
+            fmt(func.frame),
+            fmt(memo[f])
+        );
+    }
+
+    for (f, func) in funcs.iter() {
+        for pc in func.unknown.iter() {
+            println!(
+                "Warning: %rsp adjusted by an unknown amount at 0x{:03x} in {}",
+                pc,
+                name(*f)
+            );
+        }
+    }
+    cycles.sort();
+    cycles.dedup();
+    for cycle in cycles.iter() {
+        let mut path: Vec<String> = cycle.iter().map(|f| name(*f)).collect();
+        path.push(name(cycle[0]));
+        println!("Warning: recursion {}", path.join(" -> "));
+    }
+
+    let total = memo[&(hdr.entry as Address)];
+    println!("Worst-case stack usage from entry: {}", fmt(total));
+    match phdrs.iter().find(|p| p.ptype == 2) {
+        Some(stack) => {
+            println!(
+                "STACK segment: {} bytes at 0x{:03x}",
+                stack.size, stack.vaddr
+            );
+            match total {
+                Some(v) if v <= stack.size as i64 => (),
+                Some(v) => println!(
+                    "Warning: worst-case stack usage of {} bytes exceeds the STACK segment",
+                    v
+                ),
+                None => {
+                    println!("Warning: stack usage is unbounded and may overflow the STACK segment")
+                }
+            }
+        }
+        None => println!("There is no STACK segment to compare against"),
+    }
+}
+
+/// Number of shadow call stack frames shown in a report.
+const MAX_FRAMES: usize = 8;
+
+/// A call on the shadow call stack.
+struct Frame {
+    call_pc: Address,
+    callee: Address,
+    ret_addr: Address,
+    rsp: Address, // %rsp after the call, pointing at the return address
+}
+
+/// Checks stack discipline while the program runs: %rsp must stay within the STACK
+/// segment, every `ret` must return to the address pushed by its `call`, and %rsp must be
+/// back where the call left it. Each problem is reported with the shadow call stack.
+pub struct StackChecker {
+    start: Address,
+    end: Address,
+    symbols: SymTab,
+    shadow: Vec<Frame>,
+    inside: Option<bool>,
+    errors: u64,
+}
+
+impl StackChecker {
+    pub fn new(stack: &ElfPhdr, symbols: &SymTab) -> StackChecker {
+        StackChecker {
+            start: stack.vaddr as Address,
+            end: (stack.vaddr + stack.size) as Address,
+            symbols: symbols.clone(),
+            shadow: Vec::new(),
+            inside: None,
+            errors: 0,
+        }
+    }
+
+    fn name(&self, addr: Address) -> String {
+        match self.symbols.name_at(addr) {
+            Some(name) => format!("{} (0x{:03x})", name, addr),
+            None => format!("0x{:03x}", addr),
+        }
+    }
+
+    fn error(&mut self, pc: Address, msg: String) {
+        self.errors += 1;
+        println!("Stack error at 0x{:03x}: {}", pc, msg);
+        println!("  Call stack:");
+        if self.shadow.is_empty() {
+            println!("    (empty)");
+        }
+        for (i, frame) in self.shadow.iter().rev().take(MAX_FRAMES).enumerate() {
+            println!(
+                "    #{} {} called from 0x{:03x}",
+                i,
+                self.name(frame.callee),
+                frame.call_pc
+            );
+        }
+        if self.shadow.len() > MAX_FRAMES {
+            println!("    ... {} more", self.shadow.len() - MAX_FRAMES);
+        }
+    }
+}
+
+impl Hook for StackChecker {
+    fn after(&mut self, pc: Address, inst: &Inst, cpu: &Cpu) {
+        if cpu.stat != Stat::AOK && cpu.stat != Stat::HLT {
+            return;
+        }
+        let rsp = cpu.reg[Register::RSP as usize];
+
+        // the stack may be empty (rsp at the top) but not extend past either end
+        let inside = rsp >= self.start && rsp <= self.end;
+        if !inside && self.inside != Some(false) && modifies_rsp(inst) {
+            self.error(
+                pc,
+                format!(
+                    "%rsp 0x{:x} left the STACK segment 0x{:x}-0x{:x}",
+                    rsp, self.start, self.end
+                ),
+            );
+        }
+        if modifies_rsp(inst) {
+            self.inside = Some(inside);
+        }
+
+        match inst.icode {
+            Icode::CALL => self.shadow.push(Frame {
+                call_pc: pc,
+                callee: cpu.pc,
+                ret_addr: inst.val_p,
+                rsp,
+            }),
+            Icode::RET => {
+                let frame = match self.shadow.last() {
+                    Some(v) => v,
+                    None => {
+                        self.error(pc, format!("ret to 0x{:x} without a matching call", cpu.pc));
+                        return;
+                    }
+                };
+
+                if rsp != frame.rsp + 8 {
+                    let (callee, expected) = (frame.callee, frame.rsp);
+                    self.error(
+                        pc,
+                        format!(
+                            "{} returned with %rsp 0x{:x} unbalanced, expected 0x{:x}",
+                            self.name(callee),
+                            rsp - 8,
+                            expected
+                        ),
+                    );
+                }
+                let frame = self.shadow.last().unwrap();
+                if cpu.pc != frame.ret_addr {
+                    let (call_pc, expected) = (frame.call_pc, frame.ret_addr);
+                    self.error(
+                        pc,
+                        format!(
+                            "ret to 0x{:x} does not match the call at 0x{:03x}, expected 0x{:x}",
+                            cpu.pc, call_pc, expected
+                        ),
+                    );
+                }
+
+                // unwind to the frame the program actually returned to, if any
+                match self.shadow.iter().rposition(|f| f.ret_addr == cpu.pc) {
+                    Some(i) => self.shadow.truncate(i),
+                    None => {
+                        self.shadow.pop();
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn report(&self) {
+        println!("Stack check: {} error(s)", self.errors);
+    }
+}
+
+fn modifies_rsp(inst: &Inst) -> bool {
+    match inst.icode {
+        Icode::CALL | Icode::RET | Icode::IRET | Icode::PUSHQ | Icode::POPQ | Icode::LEAVE => true,
+        Icode::IRMOVQ | Icode::CMOV | Icode::OPQ | Icode::IADDQ => inst.rb == Register::RSP,
+        Icode::MRMOVQ => inst.ra == Register::RSP,
+        _ => false,
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    collections::BTreeMap,
+    io::{Cursor, Read},
+    mem::size_of,
+};
+
+use anyhow::{ensure, Result};
+use binrw::{BinRead, BinWrite};
+
+use crate::{check::ElfHdr, disas::Address};
+
+/// Section index of a symbol that is referenced but not defined.
+pub const UNDEF: u16 = 0xffff;
+
+/// Entry of the Mini-ELF symbol table, which runs from `symtab` up to `strtab`.
+#[repr(C)]
+#[derive(BinRead, BinWrite)]
+pub struct ElfSym {
+    pub name: u32,    // offset of the name in the string table
+    pub value: u32,   // virtual address
+    pub section: u16, // index of the defining program header, or UNDEF
+    pub bind: u16,    // 0 for local, 1 for global
+}
+
+/// Reads every entry of the symbol table along with its name.
+pub fn read_symbols(reader: &mut Cursor<Vec<u8>>, hdr: &ElfHdr) -> Result<Vec<(String, ElfSym)>> {
+    if hdr.symtab == 0 {
+        return Ok(Vec::new());
+    }
+    ensure!(
+        hdr.strtab > hdr.symtab,
+        "String table must follow symbol table"
+    );
+
+    let count = (hdr.strtab - hdr.symtab) as usize / size_of::<ElfSym>();
+    let mut syms = Vec::with_capacity(count);
+    for i in 0..count {
+        reader.set_position((hdr.symtab as usize + i * size_of::<ElfSym>()) as u64);
+        let sym = ElfSym::read_le(reader)?;
+        let name = read_string(reader, hdr.strtab as u64 + sym.name as u64)?;
+        syms.push((name, sym));
+    }
+
+    Ok(syms)
+}
+
+fn read_string(reader: &mut Cursor<Vec<u8>>, offset: u64) -> Result<String> {
+    reader.set_position(offset);
+
+    let mut s = String::new();
+    let mut byte = [0u8; 1];
+    loop {
+        reader.read_exact(&mut byte)?;
+        if byte[0] == 0 {
+            break;
+        }
+        s.push(byte[0] as char);
+    }
+
+    Ok(s)
+}
+
+/// Symbol names keyed by address. Only the first name seen for an address is kept.
+#[derive(Default, Clone)]
+pub struct SymTab {
+    names: BTreeMap<Address, String>,
+}
+
+impl SymTab {
+    pub fn insert(&mut self, addr: Address, name: &str) {
+        self.names.entry(addr).or_insert_with(|| name.to_string());
+    }
+
+    pub fn name_at(&self, addr: Address) -> Option<&str> {
+        self.names.get(&addr).map(|s| s.as_str())
+    }
+
+    pub fn addr_of(&self, name: &str) -> Option<Address> {
+        self.names
+            .iter()
+            .find(|(_, n)| n.as_str() == name)
+            .map(|(addr, _)| *addr)
+    }
+
+    /// Finds the closest symbol at or below `addr`, returning its name and the offset of
+    /// `addr` from it.
+    pub fn lookup(&self, addr: Address) -> Option<(&str, Address)> {
+        self.names
+            .range(..=addr)
+            .next_back()
+            .map(|(sym, name)| (name.as_str(), addr - sym))
+    }
+}
@@ -0,0 +1,502 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, ensure, Result};
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+/// Parses the subset of TOML used by test specs: `[table]` and `[[array]]` headers with
+/// dotted names, `key = value` pairs with bare or quoted keys, basic, literal and
+/// multi-line strings, decimal, hex and binary integers, booleans, arrays and inline
+/// tables. Integers may use the full 64 bits, so `0xffffffffffffffff` is -1. Dotted keys
+/// such as `regs.rax = 1` are rejected; use a `[table]` header or an inline table.
+pub fn parse(text: &str) -> Result<Table> {
+    let mut parser = Parser {
+        chars: text.chars().collect(),
+        pos: 0,
+        line: 1,
+    };
+    let mut root = Table::new();
+    let mut path: Vec<String> = Vec::new();
+
+    loop {
+        parser.skip_blank();
+        let c = match parser.peek() {
+            Some(c) => c,
+            None => break,
+        };
+
+        if c == '[' {
+            parser.pos += 1;
+            let array = parser.eat('[');
+            let header = parser.dotted_key()?;
+            parser.expect(']')?;
+            if array {
+                parser.expect(']')?;
+            }
+            let (last, parents) = header.split_last().unwrap();
+            let table = parser.open(&mut root, parents)?;
+            if array {
+                let entry = table
+                    .entry(last.clone())
+                    .or_insert_with(|| Value::Array(Vec::new()));
+                match entry {
+                    Value::Array(v) => v.push(Value::Table(Table::new())),
+                    _ => bail!("line {}: {} is not an array of tables", parser.line, last),
+                }
+            } else {
+                parser.open(table, std::slice::from_ref(last))?;
+            }
+            path = header;
+        } else {
+            let key = parser.plain_key()?;
+            parser.expect('=')?;
+            let val = parser.value()?;
+            let table = parser.open(&mut root, &path)?;
+            ensure!(
+                !table.contains_key(&key),
+                "line {}: Duplicate key {}",
+                parser.line,
+                key
+            );
+            table.insert(key, val);
+        }
+
+        parser.skip_space();
+        parser.skip_comment();
+        match parser.peek() {
+            Some('\n') | None => (),
+            Some(c) => bail!("line {}: Unexpected '{}'", parser.line, c),
+        }
+    }
+
+    Ok(root)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        self.skip_space();
+        ensure!(self.eat(c), "line {}: Expected '{}'", self.line, c);
+
+        Ok(())
+    }
+
+    fn skip_space(&mut self) {
+        while matches!(self.peek(), Some(' ' | '\t' | '\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        if self.peek() == Some('#') {
+            while !matches!(self.peek(), Some('\n') | None) {
+                self.pos += 1;
+            }
+        }
+    }
+
+    /// Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            self.skip_space();
+            self.skip_comment();
+            if !self.eat('\n') {
+                break;
+            }
+            self.line += 1;
+        }
+    }
+
+    /// Finds the table at `path` below `table`, creating tables that are missing. A name
+    /// that holds an array of tables refers to its last element.
+    fn open<'a>(&self, mut table: &'a mut Table, path: &[String]) -> Result<&'a mut Table> {
+        for name in path {
+            let entry = table
+                .entry(name.clone())
+                .or_insert_with(|| Value::Table(Table::new()));
+            table = match entry {
+                Value::Table(t) => t,
+                Value::Array(v) => match v.last_mut() {
+                    Some(Value::Table(t)) => t,
+                    _ => bail!("line {}: {} is not a table", self.line, name),
+                },
+                _ => bail!("line {}: {} is not a table", self.line, name),
+            };
+        }
+
+        Ok(table)
+    }
+
+    fn key(&mut self) -> Result<String> {
+        self.skip_space();
+        match self.peek() {
+            Some('"') | Some('\'') => self.string(),
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    self.pos += 1;
+                }
+                ensure!(self.pos > start, "line {}: Expected a key", self.line);
+                Ok(self.chars[start..self.pos].iter().collect())
+            }
+        }
+    }
+
+    /// A key of a `key = value` pair, which may not be dotted.
+    fn plain_key(&mut self) -> Result<String> {
+        let key = self.key()?;
+        self.skip_space();
+        ensure!(
+            self.peek() != Some('.'),
+            "line {}: Dotted keys are not supported, use a [{}] table instead",
+            self.line,
+            key
+        );
+
+        Ok(key)
+    }
+
+    fn dotted_key(&mut self) -> Result<Vec<String>> {
+        let mut keys = vec![self.key()?];
+        self.skip_space();
+        while self.eat('.') {
+            keys.push(self.key()?);
+            self.skip_space();
+        }
+
+        Ok(keys)
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_space();
+        let val = match self.peek() {
+            Some('"') | Some('\'') => Value::Str(self.string()?),
+            Some('[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    if self.eat(']') {
+                        break;
+                    }
+                    items.push(self.value()?);
+                    self.skip_blank();
+                    if !self.eat(',') {
+                        self.skip_blank();
+                        self.expect(']')?;
+                        break;
+                    }
+                }
+                Value::Array(items)
+            }
+            Some('{') => {
+                self.pos += 1;
+                let mut table = Table::new();
+                self.skip_space();
+                if !self.eat('}') {
+                    loop {
+                        let key = self.plain_key()?;
+                        self.expect('=')?;
+                        let val = self.value()?;
+                        ensure!(
+                            !table.contains_key(&key),
+                            "line {}: Duplicate key {}",
+                            self.line,
+                            key
+                        );
+                        table.insert(key, val);
+                        self.skip_space();
+                        if !self.eat(',') {
+                            self.expect('}')?;
+                            break;
+                        }
+                    }
+                }
+                Value::Table(table)
+            }
+            _ => {
+                let start = self.pos;
+                while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+                {
+                    self.pos += 1;
+                }
+                let word: String = self.chars[start..self.pos].iter().collect();
+                match word.as_str() {
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => Value::Int(self.int(&word)?),
+                }
+            }
+        };
+
+        Ok(val)
+    }
+
+    fn int(&self, word: &str) -> Result<i64> {
+        let digits = word.replace('_', "");
+        let (neg, digits) = match digits.strip_prefix('-') {
+            Some(v) => (true, v.to_string()),
+            None => (false, digits.trim_start_matches('+').to_string()),
+        };
+        let n = if let Some(hex) = digits.strip_prefix("0x") {
+            u64::from_str_radix(hex, 16).ok()
+        } else if let Some(bin) = digits.strip_prefix("0b") {
+            u64::from_str_radix(bin, 2).ok()
+        } else {
+            digits.parse::<u64>().ok()
+        };
+        let n = match n {
+            Some(v) => v as i64,
+            None => bail!("line {}: Expected a value, found '{}'", self.line, word),
+        };
+
+        Ok(if neg { n.wrapping_neg() } else { n })
+    }
+
+    /// Parses a basic or literal string, either of which may be multi-line.
+    fn string(&mut self) -> Result<String> {
+        let quote = self.peek().unwrap();
+        self.pos += 1;
+        let multi = self.chars[self.pos..].starts_with(&[quote, quote]);
+        if multi {
+            self.pos += 2;
+            // a newline right after the opening quotes is not part of the string
+            if self.eat('\n') {
+                self.line += 1;
+            }
+        }
+
+        let mut s = String::new();
+        loop {
+            let c = match self.peek() {
+                Some(c) => c,
+                None => bail!("line {}: Unterminated string", self.line),
+            };
+            self.pos += 1;
+            match c {
+                _ if c == quote && !multi => break,
+                _ if c == quote && self.chars[self.pos..].starts_with(&[quote, quote]) => {
+                    self.pos += 2;
+                    break;
+                }
+                '\n' if !multi => bail!("line {}: Unterminated string", self.line),
+                '\n' => {
+                    self.line += 1;
+                    s.push(c);
+                }
+                '\\' if quote == '"' => {
+                    let e = self.peek();
+                    self.pos += 1;
+                    s.push(match e {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('0') => '\0',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        _ => bail!("line {}: Unknown escape in string", self.line),
+                    });
+                }
+                _ => s.push(c),
+            }
+        }
+
+        Ok(s)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get<'a>(table: &'a Table, key: &str) -> &'a Value {
+        table
+            .get(key)
+            .unwrap_or_else(|| panic!("missing key {}", key))
+    }
+
+    fn str_of(val: &Value) -> &str {
+        match val {
+            Value::Str(s) => s,
+            _ => panic!("not a string: {:?}", val),
+        }
+    }
+
+    fn int_of(val: &Value) -> i64 {
+        match val {
+            Value::Int(n) => *n,
+            _ => panic!("not an integer: {:?}", val),
+        }
+    }
+
+    fn table_of(val: &Value) -> &Table {
+        match val {
+            Value::Table(t) => t,
+            _ => panic!("not a table: {:?}", val),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        format!("{:#}", parse(text).unwrap_err())
+    }
+
+    #[test]
+    fn strings_and_escapes() {
+        let t = parse(concat!(
+            "basic = \"a\\tb\\n\\\"c\\\" \\\\ d\"\n",
+            "literal = 'C:\\path\\n'\n",
+            "\"quoted key\" = \"\"\n",
+        ))
+        .unwrap();
+        assert_eq!(str_of(get(&t, "basic")), "a\tb\n\"c\" \\ d");
+        assert_eq!(str_of(get(&t, "literal")), "C:\\path\\n");
+        assert_eq!(str_of(get(&t, "quoted key")), "");
+
+        assert!(error("s = \"\\q\"").contains("Unknown escape"));
+        assert!(error("s = \"open\nt = 1").contains("Unterminated string"));
+    }
+
+    #[test]
+    fn multi_line_strings() {
+        let t = parse("a = \"\"\"\nline 1\nline \"2\"\n\"\"\"\nb = '''x\\ny'''\nc = 3\n").unwrap();
+        assert_eq!(str_of(get(&t, "a")), "line 1\nline \"2\"\n");
+        assert_eq!(str_of(get(&t, "b")), "x\\ny");
+        assert_eq!(int_of(get(&t, "c")), 3);
+
+        // line numbers keep counting inside multi-line strings
+        assert!(error("a = \"\"\"\n\n\"\"\"\nb = ?").starts_with("line 4:"));
+    }
+
+    #[test]
+    fn integers() {
+        let t = parse(concat!(
+            "dec = 1_000\n",
+            "hex = 0x1f8\n",
+            "bin = 0b101\n",
+            "neg = -8\n",
+            "plus = +5\n",
+            "max = 0xffffffffffffffff\n",
+            "yes = true\n",
+        ))
+        .unwrap();
+        assert_eq!(int_of(get(&t, "dec")), 1000);
+        assert_eq!(int_of(get(&t, "hex")), 0x1f8);
+        assert_eq!(int_of(get(&t, "bin")), 5);
+        assert_eq!(int_of(get(&t, "neg")), -8);
+        assert_eq!(int_of(get(&t, "plus")), 5);
+        assert_eq!(int_of(get(&t, "max")), -1);
+        assert!(matches!(get(&t, "yes"), Value::Bool(true)));
+
+        assert!(error("n = 0xg").contains("Expected a value, found '0xg'"));
+        assert!(error("n = 0x10000000000000000").contains("Expected a value"));
+    }
+
+    #[test]
+    fn arrays_and_inline_tables() {
+        let t = parse("a = [1, 2,\n  3, # three\n]\nt = { x = 1, \"y\" = 'z' }\ne = []\n").unwrap();
+        match get(&t, "a") {
+            Value::Array(v) => assert_eq!(v.iter().map(int_of).collect::<Vec<_>>(), [1, 2, 3]),
+            v => panic!("not an array: {:?}", v),
+        }
+        let inline = table_of(get(&t, "t"));
+        assert_eq!(int_of(get(inline, "x")), 1);
+        assert_eq!(str_of(get(inline, "y")), "z");
+        assert!(matches!(get(&t, "e"), Value::Array(v) if v.is_empty()));
+    }
+
+    #[test]
+    fn arrays_of_tables() {
+        let t = parse(concat!(
+            "# a spec\n",
+            "[[case]]\n",
+            "name = \"first\"\n",
+            "[case.expect]\n",
+            "stat = \"HLT\"\n",
+            "[case.expect.regs]\n",
+            "rax = 0xabcd\n",
+            "\n",
+            "[[case]]\n",
+            "name = \"second\"\n",
+            "regs = { rdi = 1 }\n",
+            "[case.expect]\n",
+            "steps = 4\n",
+        ))
+        .unwrap();
+        let cases = match get(&t, "case") {
+            Value::Array(v) => v,
+            v => panic!("not an array: {:?}", v),
+        };
+        assert_eq!(cases.len(), 2);
+
+        let first = table_of(&cases[0]);
+        assert_eq!(str_of(get(first, "name")), "first");
+        let expect = table_of(get(first, "expect"));
+        assert_eq!(str_of(get(expect, "stat")), "HLT");
+        assert_eq!(int_of(get(table_of(get(expect, "regs")), "rax")), 0xabcd);
+
+        let second = table_of(&cases[1]);
+        assert_eq!(str_of(get(second, "name")), "second");
+        assert_eq!(int_of(get(table_of(get(second, "regs")), "rdi")), 1);
+        let expect = table_of(get(second, "expect"));
+        assert_eq!(int_of(get(expect, "steps")), 4);
+        assert!(expect.get("stat").is_none());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        assert_eq!(error("a = 1\nb = 2\na = 3\n"), "line 3: Duplicate key a");
+        assert_eq!(
+            error("[[case]]\n[case.expect]\npc = 1\npc = 2\n"),
+            "line 4: Duplicate key pc"
+        );
+        assert_eq!(error("t = { x = 1, x = 2 }"), "line 1: Duplicate key x");
+
+        // the same key in different tables is fine
+        assert!(parse("[[case]]\npc = 1\n[[case]]\npc = 2\n").is_ok());
+    }
+
+    #[test]
+    fn dotted_keys_are_rejected() {
+        assert_eq!(
+            error("[[case]]\nregs.rax = 1\n"),
+            "line 2: Dotted keys are not supported, use a [regs] table instead"
+        );
+        assert!(error("t = { a.b = 1 }").contains("Dotted keys are not supported"));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert_eq!(error("a = 1 2"), "line 1: Unexpected '2'");
+        assert_eq!(error("[case\n"), "line 1: Expected ']'");
+        assert_eq!(error("= 1"), "line 1: Expected a key");
+        assert_eq!(error("a = 1\n[a]\n"), "line 2: a is not a table");
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::BTreeSet,
+    io::{self, Read, Write},
+    process::{Command, Stdio},
+};
+
+use anyhow::{ensure, Context, Result};
+
+use crate::{
+    disas::{disassemble, fetch, memtoi, Address, Cpu, Icode, Register, Stat},
+    exec::step,
+    hook::Hook,
+    load::ElfPhdr,
+    memory::Memory,
+    parse_num,
+    sym::SymTab,
+};
+
+const DISAS_LINES: usize = 16;
+const STACK_LINES: usize = 10;
+const MEM_ROWS: usize = 10;
+const LEFT_WIDTH: usize = 44;
+
+const CLEAR: &str = "\x1b[H\x1b[2J";
+const BOLD: &str = "\x1b[1m";
+const REVERSE: &str = "\x1b[7m";
+const RESET: &str = "\x1b[0m";
+
+/// Full-screen terminal front end drawn with ANSI escapes. The screen shows the
+/// disassembly around pc, the registers and flags, the stack from %rsp up to the top of
+/// the STACK segment, and a hex view of memory. It is redrawn after every command.
+pub struct Tui<'a> {
+    phdrs: &'a [ElfPhdr],
+    symbols: &'a SymTab,
+    entry: Address,
+    breakpoints: BTreeSet<Address>,
+    mem_view: Address,
+    message: String,
+    count: u64,
+}
+
+impl<'a> Tui<'a> {
+    pub fn new(entry: Address, phdrs: &'a [ElfPhdr], symbols: &'a SymTab) -> Tui<'a> {
+        // start the memory view at the first data segment
+        let mem_view = phdrs
+            .iter()
+            .find(|p| p.ptype == 0)
+            .map(|p| p.vaddr as Address)
+            .unwrap_or(0);
+
+        Tui {
+            phdrs,
+            symbols,
+            entry,
+            breakpoints: BTreeSet::new(),
+            mem_view,
+            message: String::new(),
+            count: 0,
+        }
+    }
+
+    /// Runs the program interactively until the user quits. Returns the number of
+    /// instructions executed.
+    pub fn run(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+    ) -> Result<u64> {
+        let raw = RawMode::enter()?;
+
+        loop {
+            self.draw(cpu, memory);
+            let key = match read_key() {
+                Some(v) => v,
+                None => break,
+            };
+            self.message.clear();
+
+            match key {
+                's' => self.resume(cpu, memory, hooks, true),
+                'c' => self.resume(cpu, memory, hooks, false),
+                'b' => {
+                    let addr = match self.prompt("Toggle breakpoint at (address or symbol): ") {
+                        Some(v) => v,
+                        None => cpu.pc,
+                    };
+                    if !self.breakpoints.remove(&addr) {
+                        self.breakpoints.insert(addr);
+                    }
+                }
+                'm' => {
+                    if let Some(addr) = self.prompt("Show memory at (address or symbol): ") {
+                        self.mem_view = addr;
+                    }
+                }
+                'q' | '\x03' => break,
+                _ => (),
+            }
+        }
+
+        drop(raw);
+        println!();
+        Ok(self.count)
+    }
+
+    fn resume(
+        &mut self,
+        cpu: &mut Cpu,
+        memory: &mut Memory,
+        hooks: &mut [Box<dyn Hook>],
+        single: bool,
+    ) {
+        while cpu.stat == Stat::AOK {
+            if step(cpu, memory, hooks).is_none() {
+                break;
+            }
+            self.count += 1;
+
+            if single {
+                return;
+            }
+            if self.breakpoints.contains(&cpu.pc) {
+                self.message = format!("Breakpoint at 0x{:03x}", cpu.pc);
+                return;
+            }
+        }
+
+        self.message = format!("Program stopped with status {}", cpu.stat);
+    }
+
+    /// Reads an address or symbol on the status line. Returns `None` if the input is empty
+    /// or not understood.
+    fn prompt(&mut self, text: &str) -> Option<Address> {
+        print!("{}", text);
+        io::stdout().flush().ok();
+
+        let mut line = String::new();
+        while let Some(c) = read_key() {
+            match c {
+                '\n' | '\r' => break,
+                '\x03' => {
+                    line.clear();
+                    break;
+                }
+                '\x7f' | '\x08' => {
+                    if line.pop().is_some() {
+                        print!("\x08 \x08");
+                    }
+                }
+                _ => {
+                    line.push(c);
+                    print!("{}", c);
+                }
+            }
+            io::stdout().flush().ok();
+        }
+
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        match self.symbols.addr_of(line) {
+            Some(v) => Some(v),
+            None => match parse_num(line) {
+                Ok(v) => Some(v),
+                Err(_) => {
+                    self.message = format!("Unknown address: {}", line);
+                    None
+                }
+            },
+        }
+    }
+
+    fn draw(&self, cpu: &Cpu, memory: &Memory) {
+        let mut out = String::from(CLEAR);
+
+        out += &side_by_side(&self.disassembly(cpu, memory), &registers(cpu), LEFT_WIDTH);
+        out += "\n";
+        out += &side_by_side(&self.stack(cpu, memory), &self.hex(memory), LEFT_WIDTH);
+        out += &format!("\n{}  Executed {} instructions\n", self.message, self.count);
+        out += "[s]tep  [c]ontinue  [b]reakpoint  [m]emory  [q]uit ";
+
+        print!("{}", out);
+        io::stdout().flush().ok();
+    }
+
+    fn disassembly(&self, cpu: &Cpu, memory: &Memory) -> Vec<String> {
+        // decode from the start of the code segment holding pc so instructions line up
+        let start = self
+            .phdrs
+            .iter()
+            .find(|p| {
+                p.ptype == 1
+                    && (p.vaddr as Address..(p.vaddr + p.size) as Address).contains(&cpu.pc)
+            })
+            .map(|p| (p.vaddr as Address, (p.vaddr + p.size) as Address));
+        let (start, end) = start.unwrap_or((cpu.pc, cpu.pc + (DISAS_LINES as Address) * 10));
+
+        let mut lines: Vec<(Option<Address>, String)> = Vec::new();
+        let mut fake = Cpu::new(start);
+        fake.isa = cpu.isa.clone();
+        while fake.pc < end {
+            let label = match self.symbols.name_at(fake.pc) {
+                Some(name) => Some(name),
+                None if fake.pc == self.entry => Some("_start"),
+                None => None,
+            };
+            if let Some(label) = label {
+                lines.push((None, format!("{}:", label)));
+            }
+
+            let inst = fetch(&mut fake, &memory.ram);
+            let (text, next) = if inst.icode == Icode::INVALID {
+                let byte = memory.ram.get(fake.pc as usize).copied().unwrap_or(0);
+                (format!(".byte 0x{:02x}", byte), fake.pc + 1)
+            } else {
+                (disassemble(&inst, self.symbols), inst.val_p)
+            };
+            lines.push((Some(fake.pc), text));
+            fake.pc = next;
+            fake.stat = Stat::AOK;
+        }
+
+        let at = lines
+            .iter()
+            .position(|(addr, _)| *addr == Some(cpu.pc))
+            .unwrap_or(0);
+        let first = at.saturating_sub(DISAS_LINES / 2);
+
+        let mut pane = vec![title("Disassembly")];
+        for (addr, text) in lines.iter().skip(first).take(DISAS_LINES) {
+            let line = match addr {
+                Some(addr) => {
+                    let mark = if self.breakpoints.contains(addr) {
+                        '*'
+                    } else {
+                        ' '
+                    };
+                    format!("{} 0x{:03x}:   {}", mark, addr, text)
+                }
+                None => format!("        {}", text),
+            };
+            let line = fit(&line, LEFT_WIDTH);
+            if *addr == Some(cpu.pc) {
+                pane.push(format!("{}{}{}", REVERSE, line, RESET));
+            } else {
+                pane.push(line);
+            }
+        }
+
+        pane
+    }
+
+    fn stack(&self, cpu: &Cpu, memory: &Memory) -> Vec<String> {
+        let mut pane = vec![title("Stack")];
+        let top = match self.phdrs.iter().find(|p| p.ptype == 2) {
+            Some(p) => (p.vaddr + p.size) as Address,
+            None => {
+                pane.push("no STACK segment".to_string());
+                return pane;
+            }
+        };
+
+        let rsp = cpu.reg[Register::RSP as usize];
+        let mut addr = rsp;
+        while addr < top && pane.len() <= STACK_LINES {
+            let val = match memtoi(&memory.ram, addr) {
+                Some(v) => format!("{:016x}", v),
+                None => "????????????????".to_string(),
+            };
+            let mark = if addr == rsp { "  <- %rsp" } else { "" };
+            pane.push(format!("0x{:04x}: {}{}", addr, val, mark));
+            addr += 8;
+        }
+        if addr < top {
+            pane.push("...".to_string());
+        }
+
+        pane
+    }
+
+    fn hex(&self, memory: &Memory) -> Vec<String> {
+        let mut pane = vec![title("Memory")];
+        let start = self.mem_view & !0xf;
+
+        for row in 0..MEM_ROWS {
+            let addr = start + (row as Address) * 16;
+            if addr as usize >= memory.ram.len() {
+                break;
+            }
+            let mut line = format!("0x{:04x}:", addr);
+            for i in 0..16 {
+                let a = addr + i;
+                match memory.ram.get(a as usize) {
+                    Some(_) if memory.device_at(a).is_some() => line += " --",
+                    Some(b) => line += &format!(" {:02x}", b),
+                    None => break,
+                }
+            }
+            pane.push(line);
+        }
+
+        pane
+    }
+}
+
+fn registers(cpu: &Cpu) -> Vec<String> {
+    let names = [
+        "rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi", "r8", "r9", "r10", "r11", "r12",
+        "r13", "r14",
+    ];
+
+    let mut pane = vec![title("Registers")];
+    for (name, val) in names.iter().zip(cpu.reg.iter()) {
+        pane.push(format!("{:>5}: {:016x}", format!("%{}", name), val));
+    }
+    pane.push(format!("{:>5}: {:016x}", "pc", cpu.pc));
+    pane.push(format!(
+        "flags: Z{} S{} O{}   {}",
+        cpu.zf as u8, cpu.sf as u8, cpu.of as u8, cpu.stat
+    ));
+
+    pane
+}
+
+fn title(text: &str) -> String {
+    format!("{}{}{}", BOLD, text, RESET)
+}
+
+/// Truncates or pads a line of plain text to exactly `width` characters.
+fn fit(line: &str, width: usize) -> String {
+    let line: String = line.chars().take(width).collect();
+    format!("{:<width$}", line, width = width)
+}
+
+/// Lays two panes out in columns, the left one padded to `width`.
+fn side_by_side(left: &[String], right: &[String], width: usize) -> String {
+    let mut out = String::new();
+    for i in 0..left.len().max(right.len()) {
+        let l = left.get(i).map(|l| l.as_str()).unwrap_or("");
+        out += l;
+        out += &" ".repeat(width.saturating_sub(visible_len(l)) + 2);
+        if let Some(r) = right.get(i) {
+            out += r;
+        }
+        out += "\n";
+    }
+
+    out
+}
+
+/// Counts the characters of a line that take space on screen, skipping escape sequences.
+fn visible_len(line: &str) -> usize {
+    let mut len = 0;
+    let mut escape = false;
+    for c in line.chars() {
+        match c {
+            '\x1b' => escape = true,
+            'm' if escape => escape = false,
+            _ if !escape => len += 1,
+            _ => (),
+        }
+    }
+
+    len
+}
+
+/// The terminal switched to unbuffered input without echo or signals, so Ctrl-C arrives
+/// as a key. The previous settings are restored when this is dropped, including while
+/// unwinding from a panic.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enter() -> Result<RawMode> {
+        let saved = Command::new("stty")
+            .arg("-g")
+            .stdin(Stdio::inherit())
+            .stderr(Stdio::null())
+            .output()
+            .context("Failed to run stty")?;
+        ensure!(
+            saved.status.success(),
+            "stty cannot read the terminal settings"
+        );
+        let saved = String::from_utf8_lossy(&saved.stdout).trim().to_string();
+
+        let status = Command::new("stty")
+            .args(["-icanon", "-echo", "-isig", "min", "1"])
+            .stdin(Stdio::inherit())
+            .status()
+            .context("Failed to run stty")?;
+        ensure!(status.success(), "stty cannot change the terminal settings");
+
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        Command::new("stty")
+            .arg(&self.saved)
+            .stdin(Stdio::inherit())
+            .status()
+            .ok();
+    }
+}
+
+/// Reads one character of input. Returns `None` at end of input.
+fn read_key() -> Option<char> {
+    let mut byte = [0u8];
+    match io::stdin().read(&mut byte) {
+        Ok(1) => Some(byte[0] as char),
+        _ => None,
+    }
+}
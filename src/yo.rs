@@ -0,0 +1,149 @@
+use anyhow::{bail, ensure, Context, Result};
+
+use crate::{
+    check::{self, ElfHdr},
+    error::mem_access,
+    load::{self, ElfPhdr},
+    sym::SymTab,
+};
+
+/// A line of a `.yo` listing that holds bytes.
+struct YoLine {
+    addr: u32,
+    bytes: Vec<u8>,
+    data: bool,
+}
+
+/// Parses a CS:APP `.yo` listing into memory. Contiguous runs of instructions and of data
+/// directives become CODE and DATA program headers so the listing can be treated like a
+/// Mini-ELF file, and every label becomes a symbol. A `stack` label above the program
+/// becomes the top of a STACK segment.
+pub fn read_yo(text: &str, memory: &mut [u8]) -> Result<(ElfHdr, Vec<ElfPhdr>, SymTab)> {
+    let mut symbols = SymTab::default();
+    let mut lines: Vec<YoLine> = Vec::new();
+
+    for (n, line) in text.lines().enumerate() {
+        let (left, source) = match line.split_once('|') {
+            Some(v) => v,
+            None => (line, ""),
+        };
+        let left = left.trim();
+        if left.is_empty() {
+            continue;
+        }
+
+        let (addr, hex) = left
+            .split_once(':')
+            .with_context(|| format!("Missing address on line {}", n + 1))?;
+        let addr = parse_addr(addr).with_context(|| format!("Bad address on line {}", n + 1))?;
+        let bytes =
+            parse_hex(hex.trim()).with_context(|| format!("Bad bytes on line {}", n + 1))?;
+
+        // strip comments and labels from the source
+        let mut source = match source.split_once('#') {
+            Some((v, _)) => v,
+            None => source,
+        }
+        .trim();
+        while let Some((label, rest)) = source.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+                break;
+            }
+            symbols.insert(addr as u64, label);
+            source = rest.trim();
+        }
+
+        if !bytes.is_empty() {
+            lines.push(YoLine {
+                addr,
+                bytes,
+                data: source.starts_with('.'),
+            });
+        }
+    }
+
+    let mut phdrs: Vec<ElfPhdr> = Vec::new();
+    let mut entry: Option<u32> = None;
+    for line in lines.iter() {
+        for (i, byte) in line.bytes.iter().enumerate() {
+            let addr = line.addr as usize + i;
+            *memory.get_mut(addr).context(mem_access(addr))? = *byte;
+        }
+
+        if !line.data && entry.is_none() {
+            entry = Some(line.addr);
+        }
+
+        // extend the previous segment or start a new one
+        let ptype = if line.data { 0 } else { 1 };
+        match phdrs.last_mut() {
+            Some(phdr) if phdr.ptype == ptype && phdr.vaddr + phdr.size == line.addr => {
+                phdr.size += line.bytes.len() as u32;
+            }
+            _ => phdrs.push(ElfPhdr {
+                offset: 0,
+                size: line.bytes.len() as u32,
+                vaddr: line.addr,
+                ptype,
+                flags: if line.data { 6 } else { 5 },
+                magic: load::MAGIC,
+            }),
+        }
+    }
+    ensure!(!phdrs.is_empty(), "No bytes in listing");
+
+    // the CS:APP convention is a stack label above the program, growing down to it
+    let stack = symbols.addr_of("stack").or(symbols.addr_of("Stack"));
+    if let Some(top) = stack {
+        let top = top as u32;
+        let base = phdrs
+            .iter()
+            .map(|p| p.vaddr + p.size)
+            .filter(|end| *end <= top)
+            .max()
+            .unwrap_or(0);
+        let base = (base + 7) & !7;
+        if base < top {
+            phdrs.push(ElfPhdr {
+                offset: 0,
+                size: top - base,
+                vaddr: base,
+                ptype: 2,
+                flags: 6,
+                magic: load::MAGIC,
+            });
+        }
+    }
+
+    let hdr = ElfHdr {
+        version: 1,
+        entry: entry.unwrap_or(0) as u16,
+        phdr_start: 0,
+        num_phdr: phdrs.len() as u16,
+        symtab: 0,
+        strtab: 0,
+        magic: check::MAGIC,
+    };
+
+    Ok((hdr, phdrs, symbols))
+}
+
+fn parse_addr(s: &str) -> Result<u32> {
+    let s = s.trim();
+    let digits = s.strip_prefix("0x").unwrap_or(s);
+    Ok(u32::from_str_radix(digits, 16)?)
+}
+
+fn parse_hex(s: &str) -> Result<Vec<u8>> {
+    if !s.is_ascii() || !s.len().is_multiple_of(2) {
+        bail!("Malformed hex bytes");
+    }
+
+    let mut bytes = Vec::with_capacity(s.len() / 2);
+    for i in (0..s.len()).step_by(2) {
+        bytes.push(u8::from_str_radix(&s[i..i + 2], 16)?);
+    }
+
+    Ok(bytes)
+}